@@ -0,0 +1,8262 @@
+// src/lib.rs
+//! An OpenSky node: shares CPU, memory and storage with peers over libp2p,
+//! runs their tasks in Docker and serves an HTTP API.
+//!
+//! Build one with [`OpenSkyNode::builder`], keep a [`NodeHandle`] to submit
+//! tasks and query state, and drive it with [`OpenSkyNode::run`].
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use bollard::container::{
+    Config, CreateContainerOptions, DownloadFromContainerOptions, LogOutput, LogsOptions,
+    RemoveContainerOptions, StartContainerOptions, WaitContainerOptions,
+};
+use bollard::image::CreateImageOptions;
+use bollard::models::{DeviceRequest, HostConfig};
+use bollard::Docker;
+use bytes::Buf;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::stream::BoxStream;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use libp2p::{
+    autonat::{self, NatStatus},
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{upgrade::SelectUpgrade, Boxed, MemoryTransport},
+        upgrade,
+    },
+    dns::DnsConfig,
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, IdentTopic, MessageAuthenticity,
+    },
+    identify::{Identify, IdentifyConfig, IdentifyEvent},
+    identity,
+    kad::{store::MemoryStore, Kademlia, KademliaEvent},
+    mdns::{Mdns, MdnsEvent},
+    mplex::MplexConfig,
+    multiaddr::Protocol,
+    noise,
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    relay::v2::client::{self as relay_client, Client as RelayClient},
+    request_response::{
+        ProtocolName, ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec,
+        OutboundFailure, RequestResponseConfig, RequestResponseEvent, RequestResponseMessage,
+        ResponseChannel,
+    },
+    swarm::{toggle::Toggle, AddressScore, DialError, NetworkBehaviourEventProcess, Swarm, SwarmBuilder, SwarmEvent},
+    tcp::TcpConfig,
+    yamux::YamuxConfig,
+    Multiaddr, NetworkBehaviour, PeerId, Transport,
+};
+use tracing::{debug, error, info, warn};
+use lru::LruCache;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sysinfo::{CpuExt, DiskExt, System, SystemExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc, oneshot, Notify, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio_util::io::ReaderStream;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::multipart::{FormData, Part};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Filter;
+
+/// A GPU a worker can offer to tasks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GpuInfo {
+    pub name: String,
+    pub memory_mb: u64,
+}
+
+// What kind of host this node is and which images it runs, advertised so
+// tasks land on workers that will take them
+#[derive(Debug, Clone)]
+struct Capabilities {
+    arch: String,
+    os: String,
+    gpus: Vec<GpuInfo>,
+    // None for peers too old to say
+    image_allowlist: Option<Vec<String>>,
+}
+
+impl Capabilities {
+    fn detect(image_allowlist: &[String]) -> Self {
+        Capabilities {
+            arch: docker_arch(env::consts::ARCH).to_string(),
+            os: env::consts::OS.to_string(),
+            gpus: detect_gpus(),
+            image_allowlist: Some(image_allowlist.to_vec()),
+        }
+    }
+
+    fn runs_image(&self, image: &str) -> bool {
+        self.image_allowlist.as_deref().map_or(true, |allowlist| image_allowed(image, allowlist))
+    }
+
+    // Whether an image for `platform` (`os/arch`, or just `arch`) runs here
+    fn supports(&self, platform: &str) -> bool {
+        match platform.split_once('/') {
+            Some((os, arch)) => os == self.os && arch == self.arch,
+            None => platform == self.arch,
+        }
+    }
+}
+
+// Docker names architectures differently from Rust
+fn docker_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        other => other,
+    }
+}
+
+// List NVIDIA GPUs through nvidia-smi; hosts without it have none to offer
+fn detect_gpus() -> Vec<GpuInfo> {
+    let output = match std::process::Command::new("nvidia-smi")
+        .args(["--query-gpu=name,memory.total", "--format=csv,noheader,nounits"])
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (name, memory) = line.rsplit_once(',')?;
+            Some(GpuInfo {
+                name: name.trim().to_string(),
+                memory_mb: memory.trim().parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// A task to run in a container, as sent to workers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskRequest {
+    pub task_id: String,
+    pub docker_image: String,
+    pub cpu_cores: u8,
+    pub memory_mb: u32,
+    pub command: Vec<String>,
+    pub requester_id: String,
+    /// Capped at the worker's OPENSKY_TASK_TIMEOUT_SECS
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// Platform the image is built for, e.g. `linux/arm64`; any worker if unset
+    #[serde(default)]
+    pub platform: Option<String>,
+    /// NVIDIA GPUs to pass through to the container
+    #[serde(default)]
+    pub gpus: u8,
+    /// Workers the requester runs the task on to check their results agree;
+    /// 0 or 1 for a single worker
+    #[serde(default)]
+    pub redundancy: u8,
+    /// Stored files the worker fetches and mounts at [`TASK_INPUTS_MOUNT`],
+    /// each named by its file_id
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// Paths inside the container copied into storage once it exits
+    /// successfully; the TaskResult gives their file_ids
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+/// Where a task's input files appear inside its container
+pub const TASK_INPUTS_MOUNT: &str = "/inputs";
+
+// Most input and output files a single task can name
+const MAX_TASK_INPUTS: usize = 16;
+const MAX_TASK_OUTPUTS: usize = 16;
+
+fn validate_task_outputs(outputs: &[String]) -> Result<(), String> {
+    if outputs.len() > MAX_TASK_OUTPUTS {
+        return Err(format!("at most {} outputs are allowed", MAX_TASK_OUTPUTS));
+    }
+    match outputs.iter().find(|path| !path.starts_with('/')) {
+        Some(path) => Err(format!("output {} is not an absolute path", path)),
+        None => Ok(()),
+    }
+}
+
+fn validate_task_inputs(inputs: &[String]) -> Result<(), String> {
+    if inputs.len() > MAX_TASK_INPUTS {
+        return Err(format!("at most {} inputs are allowed", MAX_TASK_INPUTS));
+    }
+    match inputs.iter().find(|file_id| !is_content_id(file_id)) {
+        Some(file_id) => Err(format!("input {} is not a file_id", file_id)),
+        None => Ok(()),
+    }
+}
+
+/// A task and where it stands, as reported in a TaskCensus
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskCensusEntry {
+    pub task_id: String,
+    pub status: TaskStatus,
+}
+
+/// The messages nodes exchange over the P2P network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OpenSkyCommand {
+    ResourceOffer {
+        cpu_cores: u8,
+        memory_mb: u64,
+        storage_gb: u32,
+        bandwidth_mbps: u32,
+        node_id: String,
+        /// Platform in Docker's naming, e.g. `amd64` on `linux`
+        #[serde(default)]
+        arch: String,
+        #[serde(default)]
+        os: String,
+        #[serde(default)]
+        gpus: Vec<GpuInfo>,
+        /// The sender's `OPENSKY_IMAGE_ALLOWLIST`
+        #[serde(default)]
+        image_allowlist: Option<Vec<String>>,
+        /// The sender's `OPENSKY_PROTOCOL_VERSION`
+        #[serde(default = "legacy_protocol_version")]
+        protocol_version: u16,
+    },
+    /// The tasks running on a node, sent along with its resource offers
+    TaskCensus {
+        node_id: String,
+        tasks: Vec<TaskCensusEntry>,
+    },
+    TaskRequest(TaskRequest),
+    TaskResult {
+        task_id: String,
+        success: bool,
+        /// Human-readable summary: the exit code followed by stdout
+        result_data: String,
+        node_id: String,
+        requester_id: String,
+        #[serde(default)]
+        stdout: String,
+        #[serde(default)]
+        stderr: String,
+        /// None if the container never exited on its own
+        #[serde(default)]
+        exit_code: Option<i64>,
+        /// The file_id each captured output was stored under, by path
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        output_files: HashMap<String, String>,
+        /// Outputs that couldn't be captured, and anything else amiss that
+        /// didn't fail the task
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+    },
+    /// A node declined to run a task, so the requester can try elsewhere
+    TaskReject {
+        task_id: String,
+        node_id: String,
+        requester_id: String,
+        reason: String,
+    },
+    /// Ask the worker running a task to stop it
+    TaskCancel {
+        task_id: String,
+        requester_id: String,
+    },
+    /// Ask the network where a task stands
+    TaskStatusRequest {
+        task_id: String,
+        requester_id: String,
+    },
+    /// A worker's answer to a TaskStatusRequest
+    TaskStatusResponse {
+        task_id: String,
+        status: TaskStatus,
+        node_id: String,
+        requester_id: String,
+    },
+    StorageRequest {
+        file_id: String,
+        size_bytes: u64,
+        node_id: String,
+        /// How long the copy should be kept; forever if unset
+        #[serde(default)]
+        ttl_secs: Option<u64>,
+    },
+    StorageOffer {
+        file_id: String,
+        node_id: String,
+        available: bool,
+        /// Why the node declined, when `available` is false
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// One piece of a file pushed to a node that accepted a StorageRequest
+    ChunkOffer {
+        file_id: String,
+        node_id: String,
+        target_id: String,
+        chunk_index: u32,
+        total_chunks: u32,
+        /// Base64-encoded bytes at offset `chunk_index * CHUNK_SIZE`
+        data: String,
+    },
+    /// Ask whoever holds a stored file to send this node a copy
+    FileFetch {
+        file_id: String,
+        node_id: String,
+    },
+    /// A holder's answer to a FileFetch
+    FileHeld {
+        file_id: String,
+        node_id: String,
+        requester_id: String,
+        size_bytes: u64,
+    },
+    /// Take up one holder's FileHeld; it then pushes the file as ChunkOffers
+    FileFetchAccept {
+        file_id: String,
+        node_id: String,
+        holder_id: String,
+    },
+    /// A command type defined outside this crate, for a custom [`CommandHandler`]
+    Custom {
+        kind: String,
+        node_id: String,
+        #[serde(default)]
+        payload: serde_json::Value,
+    },
+}
+
+impl OpenSkyCommand {
+    /// The node that published this command
+    pub fn origin(&self) -> &str {
+        match self {
+            OpenSkyCommand::TaskRequest(TaskRequest { requester_id, .. })
+            | OpenSkyCommand::TaskCancel { requester_id, .. }
+            | OpenSkyCommand::TaskStatusRequest { requester_id, .. } => requester_id,
+            OpenSkyCommand::ResourceOffer { node_id, .. }
+            | OpenSkyCommand::TaskCensus { node_id, .. }
+            | OpenSkyCommand::TaskResult { node_id, .. }
+            | OpenSkyCommand::TaskReject { node_id, .. }
+            | OpenSkyCommand::TaskStatusResponse { node_id, .. }
+            | OpenSkyCommand::StorageRequest { node_id, .. }
+            | OpenSkyCommand::StorageOffer { node_id, .. }
+            | OpenSkyCommand::ChunkOffer { node_id, .. }
+            | OpenSkyCommand::FileFetch { node_id, .. }
+            | OpenSkyCommand::FileHeld { node_id, .. }
+            | OpenSkyCommand::FileFetchAccept { node_id, .. }
+            | OpenSkyCommand::Custom { node_id, .. } => node_id,
+        }
+    }
+}
+
+// Default for the largest message we send or accept. A TaskResult carries up
+// to `max_output_bytes` each of stdout and stderr, plus the summary, signed
+// and base64 encoded, so this is well above the gossipsub default of 64 KiB.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+// The limit can't go below what a signed, base64-encoded ChunkOffer needs
+const MIN_MESSAGE_BYTES: usize = 768 * 1024;
+
+/// Major version of the wire format. Bump it whenever `OpenSkyCommand`
+/// changes in a way older nodes would misread; nodes drop messages from any
+/// other version.
+pub const OPENSKY_PROTOCOL_VERSION: u16 = 1;
+
+/// Which build a node is running
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub rustc_version: String,
+    pub protocol_version: u16,
+}
+
+impl VersionInfo {
+    // What we tell peers through Identify, e.g.
+    // `opensky/0.1.0 (3f2c1ab; protocol 1; rustc 1.68.0 ...)`
+    fn agent(&self) -> String {
+        format!(
+            "opensky/{} ({}; protocol {}; {})",
+            self.version, self.git_commit, self.protocol_version, self.rustc_version
+        )
+    }
+}
+
+/// The version of this build
+pub fn version() -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION").into(),
+        git_commit: env!("OPENSKY_GIT_COMMIT").into(),
+        rustc_version: env!("OPENSKY_RUSTC_VERSION").into(),
+        protocol_version: OPENSKY_PROTOCOL_VERSION,
+    }
+}
+
+// Nodes from before versioning speak version 1
+fn legacy_protocol_version() -> u16 {
+    1
+}
+
+// Wire format of every published message: the serialized command, signed
+// with the publishing node's ed25519 key
+#[derive(Serialize, Deserialize)]
+struct SignedEnvelope {
+    #[serde(default = "legacy_protocol_version")]
+    protocol_version: u16,
+    // The command JSON, or with `compression` set its compressed bytes in base64
+    payload: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<PayloadCompression>,
+    // Unix millis at signing time and a random value, both covered by the
+    // signature so captured messages can't be replayed later
+    timestamp: u64,
+    nonce: u64,
+    // Base64-encoded signature over the payload, timestamp and nonce
+    signature: String,
+    // Base64-encoded protobuf public key of the signer
+    pubkey: String,
+}
+
+// How an envelope's payload was compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PayloadCompression {
+    Gzip,
+}
+
+// Smaller payloads aren't worth compressing
+const COMPRESSION_THRESHOLD: usize = 512;
+
+// Gzip a large payload for the wire, base64-encoded so the envelope stays
+// JSON. Small payloads, and ones that don't shrink (such as file chunks,
+// already base64), go as they are.
+fn compress_payload(payload: String) -> Result<(String, Option<PayloadCompression>), Box<dyn Error>> {
+    if payload.len() < COMPRESSION_THRESHOLD {
+        return Ok((payload, None));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(payload.as_bytes())?;
+    let compressed = base64::encode(encoder.finish()?);
+    if compressed.len() < payload.len() {
+        Ok((compressed, Some(PayloadCompression::Gzip)))
+    } else {
+        Ok((payload, None))
+    }
+}
+
+// Undo `compress_payload`, refusing to inflate past `max_bytes`
+fn decompress_payload(
+    payload: &str,
+    compression: Option<PayloadCompression>,
+    max_bytes: usize,
+) -> Result<String, String> {
+    match compression {
+        None => Ok(payload.to_string()),
+        Some(PayloadCompression::Gzip) => {
+            let compressed = base64::decode(payload).map_err(|e| format!("invalid compressed payload: {}", e))?;
+            let mut plain = String::new();
+            GzDecoder::new(&compressed[..])
+                .take(max_bytes as u64 + 1)
+                .read_to_string(&mut plain)
+                .map_err(|e| format!("invalid compressed payload: {}", e))?;
+            if plain.len() > max_bytes {
+                return Err(format!("payload inflates past {} bytes", max_bytes));
+            }
+            Ok(plain)
+        }
+    }
+}
+
+// Why an incoming envelope was dropped
+enum EnvelopeError {
+    // Not an envelope or command we understand
+    Malformed(String),
+    // Signature or claimed origin didn't check out
+    Unverified(String),
+    // Sent by a node speaking another protocol version
+    Incompatible(u16),
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn signing_bytes(payload: &[u8], timestamp: u64, nonce: u64) -> Vec<u8> {
+    let mut bytes = payload.to_vec();
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    bytes.extend_from_slice(&nonce.to_be_bytes());
+    bytes
+}
+
+// Rejects messages outside the accepted time window and nonces we've
+// already seen from the same signer
+struct ReplayGuard {
+    window_millis: u64,
+    seen: LruCache<(PeerId, u64), ()>,
+}
+
+impl ReplayGuard {
+    fn new(window: Duration, cache_size: NonZeroUsize) -> Self {
+        ReplayGuard {
+            window_millis: window.as_millis() as u64,
+            seen: LruCache::new(cache_size),
+        }
+    }
+
+    fn check(&mut self, signer: PeerId, timestamp: u64, nonce: u64) -> Result<(), String> {
+        let now = unix_millis();
+        // Allow the same amount of clock skew into the future
+        if timestamp + self.window_millis < now || timestamp > now + self.window_millis {
+            return Err(format!("timestamp {} outside the replay window", timestamp));
+        }
+        if self.seen.put((signer, nonce), ()).is_some() {
+            return Err(format!("replayed nonce {}", nonce));
+        }
+        Ok(())
+    }
+}
+
+fn seal_envelope(keypair: &identity::Keypair, payload: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let timestamp = unix_millis();
+    let nonce = rand::random();
+    // The signature covers the payload as sent
+    let (payload, compression) = compress_payload(String::from_utf8(payload)?)?;
+    let signature = keypair.sign(&signing_bytes(payload.as_bytes(), timestamp, nonce))?;
+    let envelope = SignedEnvelope {
+        protocol_version: OPENSKY_PROTOCOL_VERSION,
+        payload,
+        compression,
+        timestamp,
+        nonce,
+        signature: base64::encode(signature),
+        pubkey: base64::encode(keypair.public().to_protobuf_encoding()),
+    };
+    Ok(serde_json::to_vec(&envelope)?)
+}
+
+// The first bytes of a message for logs: as text if it's UTF-8, else as hex
+fn message_preview(data: &[u8]) -> String {
+    const PREVIEW_BYTES: usize = 64;
+    let head = &data[..data.len().min(PREVIEW_BYTES)];
+    let ellipsis = if data.len() > PREVIEW_BYTES { "..." } else { "" };
+    match std::str::from_utf8(head) {
+        Ok(text) => format!("{:?}{}", text, ellipsis),
+        Err(_) => {
+            let hex: String = head.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}{}", hex, ellipsis)
+        }
+    }
+}
+
+// Verify the signature, freshness and that the signer is the node the
+// command claims to come from, then decompress and decode the command
+fn open_envelope(
+    data: &[u8],
+    replay_guard: &mut ReplayGuard,
+    max_payload_bytes: usize,
+) -> Result<OpenSkyCommand, EnvelopeError> {
+    let envelope: SignedEnvelope = serde_json::from_slice(data)
+        .map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+    // Checked first, since a newer payload may not even parse
+    if envelope.protocol_version != OPENSKY_PROTOCOL_VERSION {
+        return Err(EnvelopeError::Incompatible(envelope.protocol_version));
+    }
+    let pubkey = base64::decode(&envelope.pubkey)
+        .ok()
+        .and_then(|bytes| identity::PublicKey::from_protobuf_encoding(&bytes).ok())
+        .ok_or_else(|| EnvelopeError::Unverified("invalid public key".into()))?;
+    let signature = base64::decode(&envelope.signature)
+        .map_err(|_| EnvelopeError::Unverified("invalid signature encoding".into()))?;
+    let signed = signing_bytes(envelope.payload.as_bytes(), envelope.timestamp, envelope.nonce);
+    if !pubkey.verify(&signed, &signature) {
+        return Err(EnvelopeError::Unverified("bad signature".into()));
+    }
+
+    let signer = PeerId::from(pubkey);
+    replay_guard
+        .check(signer, envelope.timestamp, envelope.nonce)
+        .map_err(EnvelopeError::Unverified)?;
+
+    let payload = decompress_payload(&envelope.payload, envelope.compression, max_payload_bytes)
+        .map_err(EnvelopeError::Malformed)?;
+    let command: OpenSkyCommand = serde_json::from_str(&payload)
+        .map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+    if command.origin() != signer.to_string() {
+        return Err(EnvelopeError::Unverified(format!(
+            "signed by {} but claims to come from {}",
+            signer,
+            command.origin()
+        )));
+    }
+    Ok(command)
+}
+
+/// Where a task stands
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Unknown,
+}
+
+impl TaskStatus {
+    /// Whether the task has finished, successfully or not
+    pub fn is_terminal(self) -> bool {
+        matches!(self, TaskStatus::Completed | TaskStatus::Failed)
+    }
+}
+
+/// What this node knows about a task, as its worker or its requester
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskState {
+    pub status: TaskStatus,
+    /// The worker running the task, once known
+    pub node_id: Option<String>,
+    /// How many workers a task we submitted has been dispatched to
+    pub attempts: u32,
+    /// How the workers' results compared, for a task submitted with redundancy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quorum: Option<QuorumOutcome>,
+    /// Where the accepted result's output files were stored, by path
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub output_files: HashMap<String, String>,
+}
+
+/// A task this node ran, kept in its task history
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskRecord {
+    pub task_id: String,
+    pub docker_image: String,
+    pub requester_id: String,
+    /// This node, as the worker that ran it
+    pub node_id: String,
+    pub started_at_ms: u64,
+    pub finished_at_ms: u64,
+    pub success: bool,
+    /// None if the task never exited on its own
+    pub exit_code: Option<i64>,
+    /// The start of its result_data, at most `TASK_HISTORY_OUTPUT_BYTES`
+    pub output: String,
+}
+
+// How many finished tasks the task history keeps
+const TASK_HISTORY_CAPACITY: usize = 1000;
+
+// Output kept per task in the task history
+const TASK_HISTORY_OUTPUT_BYTES: usize = 4096;
+
+// At most `max` bytes of `text`, cut on a character boundary
+fn truncate_utf8(text: &str, max: usize) -> String {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+/// The results of a task run on several workers and whether enough agree
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuorumOutcome {
+    pub redundancy: u8,
+    /// Matching results needed to accept one: a majority of `redundancy`
+    pub required: u8,
+    /// The SHA-256 of each successful worker's exit code and result_data
+    pub results: HashMap<String, String>,
+    /// Whether a majority agreed; unset while results are still coming in
+    pub verified: Option<bool>,
+}
+
+// Where a redundant task's results stand after another one arrives
+enum Quorum {
+    Pending,
+    // Accepted, or not a redundant task at all
+    Agreed,
+    Disagreed { results: u32, agreeing: u32 },
+}
+
+// Most workers a single task can be run on at once
+const MAX_TASK_REDUNDANCY: u8 = 7;
+
+// What redundant workers' results are compared by
+fn result_digest(exit_code: Option<i64>, result_data: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{:?}\n", exit_code));
+    hasher.update(result_data);
+    format!("{:x}", hasher.finalize())
+}
+
+// A task we submitted, kept until it succeeds or runs out of retries
+struct SubmittedTask {
+    request: TaskRequest,
+    // Where the task is broadcast and which peers' offers count
+    topic: IdentTopic,
+    attempts: u32,
+    // Workers it was dispatched to directly, which won't be picked again
+    tried: HashSet<String>,
+}
+
+// What to do after a worker rejected or failed a task we submitted
+enum Retry {
+    // Not a task we dispatched to that worker
+    Ignore,
+    Again(TaskRequest),
+    GiveUp { attempts: u32 },
+}
+
+/// A task to run on the network, as submitted through `POST /api/tasks` or
+/// [`NodeHandle::submit_task`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskSubmission {
+    pub task_id: String,
+    pub docker_image: String,
+    pub cpu_cores: u8,
+    pub memory_mb: u32,
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub platform: Option<String>,
+    #[serde(default)]
+    pub gpus: u8,
+    /// Topic to run the task in; the node's first topic if unset
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Run on this many distinct workers and accept the result a majority
+    /// agree on; a single worker if unset
+    #[serde(default)]
+    pub redundancy: u8,
+    /// Stored files to mount into the container at [`TASK_INPUTS_MOUNT`]
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// Paths inside the container to keep as stored files
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+// Reject requests that are malformed or could never fit on this node, before
+// any resources are reserved for them
+fn validate_task_request(request: &TaskRequest, cpu_capacity: u8, memory_capacity: u64) -> Result<(), String> {
+    if request.cpu_cores == 0 {
+        return Err("cpu_cores must be greater than 0".into());
+    }
+    if request.memory_mb == 0 {
+        return Err("memory_mb must be greater than 0".into());
+    }
+    if request.command.is_empty() {
+        return Err("command must not be empty".into());
+    }
+    if request.cpu_cores > cpu_capacity {
+        return Err(format!(
+            "requested {} cpu cores but this node offers at most {}",
+            request.cpu_cores, cpu_capacity
+        ));
+    }
+    if request.memory_mb as u64 > memory_capacity {
+        return Err(format!(
+            "requested {} MB of memory but this node offers at most {} MB",
+            request.memory_mb, memory_capacity
+        ));
+    }
+    validate_task_env(&request.env, request.working_dir.as_deref())?;
+    validate_task_inputs(&request.inputs)?;
+    validate_task_outputs(&request.outputs)
+}
+
+// Limits on a task's environment, so a request can't balloon the container spec
+const MAX_TASK_ENV_VARS: usize = 64;
+const MAX_TASK_ENV_BYTES: usize = 16 * 1024;
+
+// Check a task's environment variables and working directory before running it
+fn validate_task_env(env: &HashMap<String, String>, working_dir: Option<&str>) -> Result<(), String> {
+    if env.len() > MAX_TASK_ENV_VARS {
+        return Err(format!("at most {} environment variables are allowed", MAX_TASK_ENV_VARS));
+    }
+    let size: usize = env.iter().map(|(name, value)| name.len() + value.len()).sum();
+    if size > MAX_TASK_ENV_BYTES {
+        return Err(format!("environment variables exceed {} bytes", MAX_TASK_ENV_BYTES));
+    }
+    for name in env.keys() {
+        let mut chars = name.chars();
+        let well_formed = chars.next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !well_formed {
+            return Err(format!("invalid environment variable name: {:?}", name));
+        }
+    }
+    if let Some(dir) = working_dir {
+        if !dir.starts_with('/') {
+            return Err(format!("working_dir must be an absolute path: {:?}", dir));
+        }
+    }
+    Ok(())
+}
+
+// Our network behavior combines Gossipsub for messaging with mDNS and
+// Kademlia for peer discovery, plus AutoNAT and relay circuits so nodes
+// behind NAT can still be reached
+#[derive(NetworkBehaviour)]
+#[behaviour(event_process = true)]
+struct OpenSkyBehaviour {
+    // Signed pub/sub messaging carrying `OpenSkyCommand` JSON payloads
+    gossipsub: Gossipsub,
+    // Local network peer discovery, unless turned off
+    mdns: Toggle<Mdns>,
+    // Wide-area peer discovery through the DHT
+    kademlia: Kademlia<MemoryStore>,
+    // Periodic round-trip measurements to connected peers
+    ping: Ping,
+    // Exchanges agent versions and addresses with connected peers
+    identify: Identify,
+    // Direct task dispatch to a chosen worker
+    task_dispatch: RequestResponse<OpenSkyCodec>,
+    // Asks peers to dial us back to learn whether we're publicly reachable
+    autonat: autonat::Behaviour,
+    // Reservations on relays, through which unreachable nodes are dialed
+    relay_client: RelayClient,
+    // Forwards decoded commands to the command loop, with the topic they came in on
+    #[behaviour(ignore)]
+    response_sender: mpsc::UnboundedSender<(String, OpenSkyCommand)>,
+    // Peer bookkeeping for the main loop to apply to the node state
+    #[behaviour(ignore)]
+    node_updates: mpsc::UnboundedSender<NodeUpdate>,
+    // Shared with the node state, so messages can be admitted on the spot
+    #[behaviour(ignore)]
+    reputation: Arc<Mutex<Reputation>>,
+    // Our own peer id, to recognise commands we published
+    #[behaviour(ignore)]
+    local_node_id: String,
+    // Drops stale and replayed messages
+    #[behaviour(ignore)]
+    replay_guard: ReplayGuard,
+    // Counts messages we couldn't decode
+    #[behaviour(ignore)]
+    metrics: Arc<Metrics>,
+    #[behaviour(ignore)]
+    max_message_bytes: usize,
+    // Files that lost a replica and need to be copied again
+    #[behaviour(ignore)]
+    replication_sender: mpsc::UnboundedSender<String>,
+    // Addresses peers saw us at, for the main loop to add to the swarm
+    #[behaviour(ignore)]
+    external_addr_sender: mpsc::UnboundedSender<Multiaddr>,
+    // Broadcasts on the topic, for tasks that can't be dispatched directly
+    #[behaviour(ignore)]
+    publisher: mpsc::UnboundedSender<(IdentTopic, Vec<u8>)>,
+    #[behaviour(ignore)]
+    topic: IdentTopic,
+    // Tasks sent to us directly, awaiting their result, keyed by requester
+    // and task_id
+    #[behaviour(ignore)]
+    pending_responses: HashMap<(String, String), ResponseChannel<OpenSkyCommand>>,
+    // Tasks we dispatched directly and haven't heard back about, with the
+    // topic to broadcast them on if the worker can't be reached
+    #[behaviour(ignore)]
+    dispatched: HashMap<RequestId, (TaskRequest, IdentTopic)>,
+    #[behaviour(ignore)]
+    allowed_peers: PeerAllowlist,
+    // Run submitted tasks here when they fit, before looking for a worker
+    #[behaviour(ignore)]
+    prefer_local: bool,
+}
+
+// A change to the node state, made by the main loop on behalf of the swarm
+type NodeUpdate = Box<dyn FnOnce(&mut NodeState) + Send>;
+
+impl OpenSkyBehaviour {
+    // The swarm's callbacks are synchronous and must never wait for the node
+    // lock, so what they learn is queued for the main loop to apply
+    fn update(&self, update: impl FnOnce(&mut NodeState) + Send + 'static) {
+        let _ = self.node_updates.send(Box::new(update));
+    }
+
+    // Commands go to the command loop through the same queue, so they're
+    // handled only once what the swarm learnt before them has been applied
+    fn forward(&self, topic: String, command: OpenSkyCommand) {
+        let response_sender = self.response_sender.clone();
+        self.update(move |_| {
+            let _ = response_sender.send((topic, command));
+        });
+    }
+
+    fn reputation(&self) -> std::sync::MutexGuard<'_, Reputation> {
+        self.reputation.lock().unwrap()
+    }
+
+    // The topic a task was submitted to, or our default one
+    fn task_topic(&self, node: &NodeState, task_id: &str) -> IdentTopic {
+        node.submitted_tasks
+            .get(task_id)
+            .map_or_else(|| self.topic.clone(), |submitted| submitted.topic.clone())
+    }
+
+    // Send a task to the best-suited worker in its topic, or broadcast it
+    // there if no known peer can run it. A redundant task first goes to that
+    // many distinct workers; each retry replaces one of them. With
+    // `prefer_local`, a task that fits here runs here first, and its retries
+    // go to other workers. Called from the main loop, which holds the lock.
+    fn dispatch(&mut self, node: &mut NodeState, request: TaskRequest) {
+        let topic = self.task_topic(node, &request.task_id);
+        let (workers, wanted, local) = {
+            let (mut tried, first) = match node.submitted_tasks.get(&request.task_id) {
+                Some(submitted) => (submitted.tried.clone(), submitted.attempts == 0),
+                None => (HashSet::new(), true),
+            };
+            let wanted = if first { request.redundancy.max(1) as usize } else { 1 };
+            let local = first && self.prefer_local && node.fits_locally(&request);
+            if local {
+                tried.insert(self.local_node_id.clone());
+            }
+            let mut workers = Vec::new();
+            while workers.len() + (local as usize) < wanted {
+                match schedule_task(node, &request, &tried, topic.hash().as_str()) {
+                    Some(worker) => {
+                        tried.insert(worker.to_string());
+                        workers.push(worker);
+                    }
+                    None => break,
+                }
+            }
+            let attempts = match node.submitted_tasks.get_mut(&request.task_id) {
+                Some(submitted) => {
+                    submitted.attempts += (workers.len() + local as usize).max(1) as u32;
+                    submitted.tried = tried;
+                    submitted.attempts
+                }
+                None => 1,
+            };
+            let assigned = if local {
+                Some(self.local_node_id.clone())
+            } else {
+                workers.first().map(|w| w.to_string())
+            };
+            node.set_task_state(&request.task_id, TaskStatus::Queued, assigned);
+            if let Some(state) = node.task_states.get_mut(&request.task_id) {
+                state.attempts = attempts;
+            }
+            (workers, wanted, local)
+        };
+        if local {
+            info!(task_id = %request.task_id, "Running task {} on this node", request.task_id);
+            let command = OpenSkyCommand::TaskRequest(request.clone());
+            self.forward(topic.hash().into_string(), command);
+        }
+        for worker in &workers {
+            info!(task_id = %request.task_id, peer_id = %worker, "Dispatching task {} to {}", request.task_id, worker);
+            let request_id = self.task_dispatch.send_request(worker, request.clone());
+            self.dispatched.insert(request_id, (request.clone(), topic.clone()));
+        }
+        // Whichever copies no known peer could take go to the topic
+        if workers.len() + (local as usize) < wanted {
+            self.broadcast_task(request, topic);
+        }
+    }
+
+    fn broadcast_task(&mut self, request: TaskRequest, topic: IdentTopic) {
+        info!(task_id = %request.task_id, topic = %topic, "Broadcasting task {} on {}", request.task_id, topic);
+        let json = serde_json::to_vec(&OpenSkyCommand::TaskRequest(request)).expect("Failed to serialize");
+        let _ = self.publisher.send((topic, json));
+    }
+
+    // Answer a directly dispatched task with its result on the stream it
+    // came in on, or hand the result of a task we ran for ourselves back to
+    // the command loop. Returns false if the message should be published
+    // instead.
+    fn respond_directly(&mut self, topic: &IdentTopic, data: &[u8]) -> bool {
+        let response = match serde_json::from_slice::<OpenSkyCommand>(data) {
+            Ok(response @ OpenSkyCommand::TaskResult { .. }) | Ok(response @ OpenSkyCommand::TaskReject { .. }) => response,
+            _ => return false,
+        };
+        let (task_id, requester_id) = match &response {
+            OpenSkyCommand::TaskResult { task_id, requester_id, .. }
+            | OpenSkyCommand::TaskReject { task_id, requester_id, .. } => (task_id.clone(), requester_id.clone()),
+            _ => return false,
+        };
+        if requester_id == self.local_node_id {
+            self.forward(topic.hash().into_string(), response);
+            return true;
+        }
+        match self.pending_responses.remove(&(requester_id, task_id)) {
+            // The requester may have given up waiting; fall back to the topic
+            Some(channel) => self.task_dispatch.send_response(channel, response).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<GossipsubEvent> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message { propagation_source, message, .. } = event {
+            // Messages are signed, so the source is the peer that wrote it
+            let author = message.source.unwrap_or(propagation_source);
+            if !self.allowed_peers.allows(&author) {
+                warn!("Ignoring message from {}: not an allowed peer", author);
+                return;
+            }
+            let sender = author.to_string();
+            let source = propagation_source.to_string();
+            self.update(move |node| {
+                node.peer_last_seen.insert(source, Instant::now());
+            });
+            let admission = self.reputation().record_message(&sender);
+            match admission {
+                Admission::Accepted => {}
+                Admission::Banned => return,
+                Admission::RateLimited => {
+                    self.metrics.rate_limited_messages.inc();
+                    return;
+                }
+            }
+            // Gossipsub enforces the same limit, but never parse more than we allow
+            if message.data.len() > self.max_message_bytes {
+                warn!(
+                    "Dropping {} byte message from {}: over the {} byte limit",
+                    message.data.len(),
+                    sender,
+                    self.max_message_bytes
+                );
+                self.metrics.oversized_messages.inc();
+                return;
+            }
+            let command = match open_envelope(&message.data, &mut self.replay_guard, self.max_message_bytes) {
+                Ok(command) => command,
+                Err(EnvelopeError::Unverified(reason)) => {
+                    error!("Dropping unverified message from {}: {}", propagation_source, reason);
+                    self.reputation().record_malformed(&sender);
+                    return;
+                }
+                Err(EnvelopeError::Incompatible(version)) => {
+                    // Not the peer's fault, so its reputation is left alone
+                    warn!(
+                        "Dropping message from {}: protocol version {}, we speak {}",
+                        sender, version, OPENSKY_PROTOCOL_VERSION
+                    );
+                    return;
+                }
+                Err(EnvelopeError::Malformed(reason)) => {
+                    // Usually a peer on another protocol version
+                    warn!(
+                        "Dropping undecodable message from {}: {} (preview: {})",
+                        sender,
+                        reason,
+                        message_preview(&message.data)
+                    );
+                    self.metrics.invalid_messages.inc();
+                    self.reputation().record_malformed(&sender);
+                    return;
+                }
+            };
+
+            // Peers can relay back what we published ourselves
+            if command.origin() == self.local_node_id {
+                return;
+            }
+            // Results are only of interest to the node that requested the task
+            if let OpenSkyCommand::TaskResult { requester_id, .. }
+            | OpenSkyCommand::TaskReject { requester_id, .. }
+            | OpenSkyCommand::TaskStatusResponse { requester_id, .. } = &command
+            {
+                if requester_id != &self.local_node_id {
+                    return;
+                }
+            }
+            info!("Received command on {}: {:?}", message.topic, command);
+            self.forward(message.topic.into_string(), command);
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(peers) => {
+                for (peer_id, addr) in peers {
+                    if !self.allowed_peers.allows(&peer_id) {
+                        debug!("Ignoring discovered peer {}: not an allowed peer", peer_id);
+                        continue;
+                    }
+                    info!(peer_id = %peer_id, "Discovered peer: {}", peer_id);
+                    self.kademlia.add_address(&peer_id, addr);
+                    let peer = peer_id.to_string();
+                    self.update(move |node| {
+                        node.peer_discovery.entry(peer.clone()).or_insert(Discovery::Mdns);
+                        node.peer_last_seen.insert(peer.clone(), Instant::now());
+                        if node.peers.insert(peer.clone()) {
+                            node.events.record(NodeEvent::PeerDiscovered { peer_id: peer });
+                        }
+                    });
+                    self.gossipsub.add_explicit_peer(&peer_id);
+                }
+            }
+            MdnsEvent::Expired(peers) => {
+                for (peer_id, _addr) in peers {
+                    info!(peer_id = %peer_id, "Peer expired: {}", peer_id);
+                    let peer = peer_id.to_string();
+                    let replication_sender = self.replication_sender.clone();
+                    self.update(move |node| {
+                        if node.peers.remove(&peer) {
+                            node.events.record(NodeEvent::PeerExpired { peer_id: peer.clone() });
+                        }
+                        if !node.peer_connections.contains_key(&peer) {
+                            node.peer_discovery.remove(&peer);
+                        }
+                        // Its resources are no longer reachable
+                        node.network_resources.remove(&peer);
+                        for (file_id, replicas) in node.file_replicas.iter_mut() {
+                            if replicas.remove(&peer) {
+                                let _ = replication_sender.send(file_id.clone());
+                            }
+                        }
+                    });
+                    self.gossipsub.remove_explicit_peer(&peer_id);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::RoutingUpdated { peer, is_new_peer, addresses, .. } = event {
+            if !self.allowed_peers.allows(&peer) {
+                self.kademlia.remove_peer(&peer);
+                return;
+            }
+            let addresses: Vec<Multiaddr> = addresses.iter().cloned().collect();
+            let peer_id = peer.to_string();
+            self.update(move |node| {
+                node.peer_store.learn(&peer_id, addresses.iter());
+                if is_new_peer {
+                    node.peer_discovery.entry(peer_id).or_insert(Discovery::Kademlia);
+                }
+            });
+            if !is_new_peer {
+                return;
+            }
+            info!(peer_id = %peer, "Discovered peer via Kademlia: {}", peer);
+            self.gossipsub.add_explicit_peer(&peer);
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<TaskRequest, OpenSkyCommand>> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<TaskRequest, OpenSkyCommand>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request { request, channel, .. } => {
+                    if !self.allowed_peers.allows(&peer) {
+                        warn!("Ignoring task request from {}: not an allowed peer", peer);
+                        return;
+                    }
+                    let admission = self.reputation().record_message(&peer.to_string());
+                    match admission {
+                        Admission::Accepted => {}
+                        Admission::Banned => return,
+                        Admission::RateLimited => {
+                            self.metrics.rate_limited_messages.inc();
+                            return;
+                        }
+                    }
+                    // The connection authenticates the peer, which must be the requester
+                    if request.requester_id != peer.to_string() {
+                        error!(task_id = %request.task_id, peer_id = %peer, "Dropping task {} from {}: requester mismatch", request.task_id, peer);
+                        return;
+                    }
+                    info!(task_id = %request.task_id, peer_id = %peer, "Received direct task request {} from {}", request.task_id, peer);
+                    let key = (peer.to_string(), request.task_id.clone());
+                    // A repeat must not take over the stream the first copy answers on
+                    if self.pending_responses.contains_key(&key) {
+                        let reject = OpenSkyCommand::TaskReject {
+                            task_id: request.task_id,
+                            node_id: self.local_node_id.clone(),
+                            requester_id: request.requester_id,
+                            reason: "task is already pending here".into(),
+                        };
+                        let _ = self.task_dispatch.send_response(channel, reject);
+                        return;
+                    }
+                    self.pending_responses.insert(key, channel);
+                    self.forward(self.topic.hash().into_string(), OpenSkyCommand::TaskRequest(request));
+                }
+                RequestResponseMessage::Response { request_id, response } => {
+                    self.dispatched.remove(&request_id);
+                    if response.origin() != peer.to_string() {
+                        error!("Dropping task response from {}: origin mismatch", peer);
+                        return;
+                    }
+                    self.forward(self.topic.hash().into_string(), response);
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                let (request, topic) = match self.dispatched.remove(&request_id) {
+                    Some(dispatched) => dispatched,
+                    None => return,
+                };
+                error!(task_id = %request.task_id, peer_id = %peer, "Direct dispatch of task {} to {} failed: {:?}", request.task_id, peer, error);
+                // Only retry over the topic if the worker never got the task;
+                // otherwise its result can still arrive on the topic
+                if matches!(
+                    error,
+                    OutboundFailure::DialFailure | OutboundFailure::UnsupportedProtocols
+                ) {
+                    self.broadcast_task(request, topic);
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                error!("Inbound task request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: PingEvent) {
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                let peer = event.peer.to_string();
+                self.update(move |node| {
+                    node.peer_rtts.insert(peer.clone(), rtt);
+                    node.peer_last_seen.insert(peer, Instant::now());
+                });
+            }
+            Ok(PingSuccess::Pong) => {
+                let peer = event.peer.to_string();
+                self.update(move |node| {
+                    node.peer_last_seen.insert(peer, Instant::now());
+                });
+            }
+            Err(e) => info!("Ping to {} failed: {}", event.peer, e),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            info!("Identified {} as {} at {}", peer_id, info.agent_version, info.observed_addr);
+            let _ = self.external_addr_sender.send(info.observed_addr.clone());
+            self.update(move |node| {
+                node.peer_store.learn(&peer_id.to_string(), &info.listen_addrs);
+                node.peer_identities.insert(peer_id.to_string(), PeerIdentity {
+                    protocol_version: info.protocol_version,
+                    agent_version: info.agent_version,
+                    listen_addrs: info.listen_addrs.iter().map(|addr| addr.to_string()).collect(),
+                    observed_addr: info.observed_addr.to_string(),
+                });
+            });
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<autonat::Event> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: autonat::Event) {
+        if let autonat::Event::StatusChanged { new, .. } = event {
+            match new {
+                NatStatus::Public(addr) => info!("NAT status: publicly reachable at {}", addr),
+                NatStatus::Private => info!("NAT status: behind NAT, reachable only through relays"),
+                NatStatus::Unknown => info!("NAT status: unknown"),
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<relay_client::Event> for OpenSkyBehaviour {
+    fn inject_event(&mut self, event: relay_client::Event) {
+        match event {
+            relay_client::Event::ReservationReqAccepted { relay_peer_id, .. } => {
+                info!("Reserved a relay slot on {}", relay_peer_id);
+            }
+            relay_client::Event::ReservationReqFailed { relay_peer_id, error, .. } => {
+                error!("Relay {} refused a reservation: {:?}", relay_peer_id, error);
+            }
+            event => info!("Relay event: {:?}", event),
+        }
+    }
+}
+
+// TCP with DNS resolution, or a circuit through a relay; either way secured
+// with Noise and multiplexed like `libp2p::development_transport`. With
+// `in_memory` TCP is swapped for the in-process transport, so nodes sharing a
+// process can dial each other at `/memory/<port>`.
+async fn build_transport(
+    id_keys: &identity::Keypair,
+    relay_transport: relay_client::ClientTransport,
+    in_memory: bool,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error>> {
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new().into_authentic(id_keys)?;
+    let noise = noise::NoiseConfig::xx(noise_keys).into_authenticated();
+    let muxer = SelectUpgrade::new(YamuxConfig::default(), MplexConfig::default());
+    let timeout = Duration::from_secs(20);
+    if in_memory {
+        return Ok(relay_transport
+            .or_transport(MemoryTransport::default())
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise)
+            .multiplex(muxer)
+            .timeout(timeout)
+            .boxed());
+    }
+    let dns_tcp = DnsConfig::system(TcpConfig::new().nodelay(true)).await?;
+    Ok(relay_transport
+        .or_transport(dns_tcp)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise)
+        .multiplex(muxer)
+        .timeout(timeout)
+        .boxed())
+}
+
+// Protocol for sending a TaskRequest straight to the chosen worker, which
+// answers with its TaskResult or TaskReject on the same stream
+#[derive(Debug, Clone)]
+struct OpenSkyProtocol;
+
+impl ProtocolName for OpenSkyProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/opensky/task/1.0.0"
+    }
+}
+
+// Length-prefixed JSON, the same encoding as on the gossipsub topic. The
+// connection is already authenticated, so messages aren't wrapped in a
+// SignedEnvelope.
+#[derive(Clone)]
+struct OpenSkyCodec {
+    max_message_bytes: usize,
+}
+
+#[async_trait]
+impl RequestResponseCodec for OpenSkyCodec {
+    type Protocol = OpenSkyProtocol;
+    type Request = TaskRequest;
+    type Response = OpenSkyCommand;
+
+    async fn read_request<T>(&mut self, _: &OpenSkyProtocol, io: &mut T) -> std::io::Result<TaskRequest>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let bytes = upgrade::read_length_prefixed(io, self.max_message_bytes).await?;
+        serde_json::from_slice(&bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(&mut self, _: &OpenSkyProtocol, io: &mut T) -> std::io::Result<OpenSkyCommand>
+    where
+        T: futures::AsyncRead + Unpin + Send,
+    {
+        let bytes = upgrade::read_length_prefixed(io, self.max_message_bytes).await?;
+        serde_json::from_slice(&bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(&mut self, _: &OpenSkyProtocol, io: &mut T, request: TaskRequest) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        upgrade::write_length_prefixed(io, serde_json::to_vec(&request)?).await?;
+        futures::AsyncWriteExt::close(io).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &OpenSkyProtocol,
+        io: &mut T,
+        response: OpenSkyCommand,
+    ) -> std::io::Result<()>
+    where
+        T: futures::AsyncWrite + Unpin + Send,
+    {
+        upgrade::write_length_prefixed(io, serde_json::to_vec(&response)?).await?;
+        futures::AsyncWriteExt::close(io).await
+    }
+}
+
+// Higher is better: the CPU cores and GB of memory a worker would have
+// spare after taking the task, less one point per 100 ms of ping round trip.
+// Peers we haven't measured yet are charged as if 100 ms away.
+fn worker_score(record: &ResourceRecord, request: &TaskRequest, rtt: Option<Duration>) -> f64 {
+    let spare_cores = record.cpu_cores.saturating_sub(request.cpu_cores) as f64;
+    let spare_memory_gb = record.memory_mb.saturating_sub(request.memory_mb as u64) as f64 / 1024.0;
+    let latency = rtt.map_or(1.0, |rtt| rtt.as_secs_f64() * 10.0);
+    spare_cores + spare_memory_gb - latency
+}
+
+// Whether the connected, unbanned worker `node_id` in `topic` could take
+// `request` now: its latest offer meets the CPU, memory, GPU, platform and
+// protocol requirements and its allowlist admits the image
+fn worker_fits(node: &NodeState, node_id: &str, record: &ResourceRecord, request: &TaskRequest, topic: &str) -> bool {
+    node.peers.contains(node_id)
+        && !node.reputation.lock().unwrap().is_banned(node_id)
+        && request
+            .platform
+            .as_deref()
+            .map_or(true, |platform| record.capabilities.supports(platform))
+        && record.capabilities.runs_image(&request.docker_image)
+        && record.protocol_version == OPENSKY_PROTOCOL_VERSION
+        && record.topics.contains(topic)
+        && record.last_seen.elapsed() < RESOURCE_OFFER_TTL
+        && record.cpu_cores >= request.cpu_cores
+        && record.memory_mb >= request.memory_mb as u64
+        && record.capabilities.gpus.len() >= request.gpus as usize
+}
+
+// Every worker in `topic` that could take `request` now, best first
+fn task_candidates(node: &NodeState, request: &TaskRequest, topic: &str) -> Vec<String> {
+    let mut candidates: Vec<_> = node
+        .network_resources
+        .iter()
+        .filter(|(node_id, record)| worker_fits(node, node_id, record, request, topic))
+        .map(|(node_id, record)| {
+            let trusted = node.reputation.lock().unwrap().is_trusted(node_id);
+            (node_id, trusted, worker_score(record, request, node.peer_rtts.get(node_id).copied()))
+        })
+        .collect();
+    candidates.sort_by(|(_, trusted_a, a), (_, trusted_b, b)| trusted_b.cmp(trusted_a).then(b.total_cmp(a)));
+    candidates.into_iter().map(|(node_id, _, _)| node_id.clone()).collect()
+}
+
+// Place `request` on the best-scoring worker in `topic` that fits it, skipping
+// those in `exclude`. Peers with a poor reputation are only chosen when
+// nobody else fits. `None` means no known worker fits and the task should be
+// broadcast instead.
+fn schedule_task(node: &NodeState, request: &TaskRequest, exclude: &HashSet<String>, topic: &str) -> Option<PeerId> {
+    task_candidates(node, request, topic)
+        .into_iter()
+        .filter(|node_id| !exclude.contains(node_id))
+        .find_map(|node_id| node_id.parse().ok())
+}
+
+// The peers of a closed, permissioned cluster; anyone if unset
+#[derive(Clone, Default)]
+struct PeerAllowlist(Option<Arc<HashSet<PeerId>>>);
+
+impl PeerAllowlist {
+    fn parse(peers: &[String]) -> Result<Self, Box<dyn Error>> {
+        if peers.is_empty() {
+            return Ok(PeerAllowlist(None));
+        }
+        let peers = peers
+            .iter()
+            .map(|peer| peer.parse().map_err(|e| format!("invalid peer id {} in OPENSKY_ALLOWED_PEERS: {}", peer, e)))
+            .collect::<Result<HashSet<PeerId>, _>>()?;
+        Ok(PeerAllowlist(Some(Arc::new(peers))))
+    }
+
+    fn allows(&self, peer: &PeerId) -> bool {
+        self.0.as_ref().map_or(true, |peers| peers.contains(peer))
+    }
+}
+
+// Parse a bootstrap multiaddr, which must end in `/p2p/<peer id>`
+fn parse_bootstrap_addr(addr: &str) -> Option<(PeerId, Multiaddr)> {
+    let addr: Multiaddr = addr.trim().parse().ok()?;
+    match addr.iter().last()? {
+        Protocol::P2p(hash) => Some((PeerId::from_multihash(hash).ok()?, addr)),
+        _ => None,
+    }
+}
+
+// Peers kept in the peer store, most recently seen first when it's full
+const PEER_STORE_CAPACITY: usize = 100;
+
+// Addresses kept per peer in the peer store
+const PEER_STORE_ADDRESSES_PER_PEER: usize = 8;
+
+// Failed dials after which an address is dropped from the peer store
+const PEER_STORE_MAX_FAILURES: u32 = 3;
+
+// Peers from the peer store dialled at startup
+const PEER_STORE_DIAL_COUNT: usize = 8;
+
+// Peers we've learned addresses for, saved as `peers.json` in the data
+// directory so a restart can reconnect without relying on bootstrap peers alone
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PeerStore {
+    peers: HashMap<String, KnownPeer>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct KnownPeer {
+    // Each address without its `/p2p/` suffix, with its dial failures in a row
+    addresses: HashMap<String, u32>,
+    last_seen_secs: u64,
+}
+
+// An address as kept in the peer store, without a trailing `/p2p/<peer id>`
+fn peer_store_addr(addr: &Multiaddr) -> String {
+    addr.iter()
+        .filter(|protocol| !matches!(protocol, Protocol::P2p(_)))
+        .collect::<Multiaddr>()
+        .to_string()
+}
+
+impl PeerStore {
+    // A missing or unreadable file is an empty store, not a reason not to start
+    fn load(path: &Path) -> Self {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable peer store {}: {}", path.display(), e);
+                PeerStore::default()
+            }),
+            Err(_) => PeerStore::default(),
+        }
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    fn learn<'a>(&mut self, peer: &str, addrs: impl IntoIterator<Item = &'a Multiaddr>) {
+        let known = self.peers.entry(peer.to_string()).or_default();
+        known.last_seen_secs = unix_secs();
+        for addr in addrs {
+            if known.addresses.len() >= PEER_STORE_ADDRESSES_PER_PEER {
+                break;
+            }
+            known.addresses.entry(peer_store_addr(addr)).or_insert(0);
+        }
+        if self.peers.len() > PEER_STORE_CAPACITY {
+            let stalest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, known)| known.last_seen_secs)
+                .map(|(peer, _)| peer.clone());
+            if let Some(stalest) = stalest {
+                self.peers.remove(&stalest);
+            }
+        }
+        self.dirty = true;
+    }
+
+    // We reached `peer` at `addr`, which is good again however often it failed
+    fn connected(&mut self, peer: &str, addr: &Multiaddr) {
+        self.learn(peer, [addr]);
+        if let Some(failures) = self.peers.get_mut(peer).and_then(|known| known.addresses.get_mut(&peer_store_addr(addr))) {
+            *failures = 0;
+        }
+    }
+
+    fn dial_failed(&mut self, peer: &str, addr: &Multiaddr) {
+        let known = match self.peers.get_mut(peer) {
+            Some(known) => known,
+            None => return,
+        };
+        let addr = peer_store_addr(addr);
+        if let Some(failures) = known.addresses.get_mut(&addr) {
+            *failures += 1;
+            if *failures >= PEER_STORE_MAX_FAILURES {
+                info!("Dropping {} for {} from the peer store after {} failed dials", addr, peer, failures);
+                known.addresses.remove(&addr);
+            }
+            if known.addresses.is_empty() {
+                self.peers.remove(peer);
+            }
+            self.dirty = true;
+        }
+    }
+
+    // The `count` most recently seen peers, with every address to dial them at
+    fn dial_candidates(&self, count: usize) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut peers: Vec<(&String, &KnownPeer)> = self.peers.iter().collect();
+        peers.sort_by_key(|(_, known)| std::cmp::Reverse(known.last_seen_secs));
+        peers
+            .into_iter()
+            .filter_map(|(peer, known)| {
+                let peer: PeerId = peer.parse().ok()?;
+                let addrs = known.addresses.keys().filter_map(|addr| addr.parse().ok()).collect();
+                Some((peer, addrs))
+            })
+            .take(count)
+            .collect()
+    }
+}
+
+// What a peer told us about itself through the Identify protocol
+#[derive(Debug, Clone, Serialize)]
+struct PeerIdentity {
+    protocol_version: String,
+    agent_version: String,
+    listen_addrs: Vec<String>,
+    // Where the peer sees us connecting from
+    observed_addr: String,
+}
+
+// How we first came across a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Discovery {
+    Mdns,
+    Kademlia,
+    // A configured bootstrap peer or relay
+    Dial,
+    // It connected to us
+    Inbound,
+}
+
+// Our open connections to a peer
+struct PeerConnection {
+    addresses: Vec<String>,
+    connected_since: SystemTime,
+}
+
+/// Exit code and captured output of a finished task
+#[derive(Debug, Clone)]
+pub struct TaskOutput {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+    /// Contents of the task's requested outputs that exist, by path
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+/// What came of running a task: its output, or why it couldn't be run
+pub type TaskOutcome = Result<TaskOutput, String>;
+
+/// Runs the tasks a node accepts, once they've been validated and their
+/// resources reserved. Chosen by [`NodeConfig::executor`] unless the builder
+/// is given one with [`OpenSkyNodeBuilder::executor`].
+#[async_trait]
+pub trait TaskExecutor: Send + Sync {
+    /// Run a task. `inputs`, for a task with inputs, is a directory holding
+    /// them to be made available read-only at [`TASK_INPUTS_MOUNT`].
+    async fn execute(&self, request: &TaskRequest, inputs: Option<&Path>) -> TaskOutcome;
+
+    /// Clean up after a task whose `execute` was abandoned because it timed
+    /// out or was cancelled
+    async fn abort(&self, _task_id: &str) {}
+
+    /// Follow a running task's output line by line, from its start, until
+    /// the task exits; `None` if the executor can't
+    async fn follow_logs(&self, _task_id: &str) -> Option<BoxStream<'static, String>> {
+        None
+    }
+}
+
+// Append as much of `chunk` as fits in `max` bytes
+fn append_capped(buf: &mut Vec<u8>, chunk: &[u8], max: usize) {
+    let room = max.saturating_sub(buf.len());
+    buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+}
+
+// Whether `image` matches an allowlist entry. Entries are image names, which
+// match any tag or digest of that image, or `prefix*` wildcards such as `org/*`.
+fn image_allowed(image: &str, allowlist: &[String]) -> bool {
+    allowlist.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => image.starts_with(prefix),
+        None => {
+            image == pattern
+                || image
+                    .strip_prefix(pattern.as_str())
+                    .map_or(false, |rest| rest.starts_with(':') || rest.starts_with('@'))
+        }
+    })
+}
+
+fn container_name(task_id: &str) -> String {
+    format!("opensky-{}", task_id)
+}
+
+// Kill and remove a task's container, used when the task is abandoned early
+async fn force_remove_container(docker: &Docker, task_id: &str) {
+    if let Err(e) = docker
+        .remove_container(
+            &container_name(task_id),
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await
+    {
+        error!("Failed to remove container for {}: {}", task_id, e);
+    }
+}
+
+// What to run for a task and the limits to run it under
+struct ContainerSpec {
+    image: String,
+    command: Vec<String>,
+    env: HashMap<String, String>,
+    working_dir: Option<String>,
+    cpu_cores: u8,
+    memory_mb: u32,
+    gpus: u8,
+    inputs: Option<PathBuf>,
+    outputs: Vec<String>,
+}
+
+// Pull the image, run the command with the requested CPU and memory limits,
+// wait for it to exit and collect up to `max_output` bytes each of stdout and
+// stderr, and the output files if it succeeded. The container is always
+// removed.
+async fn run_container(
+    docker: &Docker,
+    task_id: &str,
+    spec: ContainerSpec,
+    max_output: usize,
+) -> Result<TaskOutput, bollard::errors::Error> {
+    let mut pull = docker.create_image(
+        Some(CreateImageOptions {
+            from_image: spec.image.as_str(),
+            ..Default::default()
+        }),
+        None,
+        None,
+    );
+    while let Some(progress) = pull.next().await {
+        progress?;
+    }
+
+    let env: Vec<String> = spec.env.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    let config = Config {
+        image: Some(spec.image.clone()),
+        cmd: if spec.command.is_empty() { None } else { Some(spec.command.clone()) },
+        env: if env.is_empty() { None } else { Some(env) },
+        working_dir: spec.working_dir.clone(),
+        host_config: Some(HostConfig {
+            // Equivalent of `--cpus`, `--memory` and `--gpus <n>`
+            nano_cpus: Some(spec.cpu_cores as i64 * 1_000_000_000),
+            memory: Some(spec.memory_mb as i64 * 1024 * 1024),
+            // The path is on this host, so a node that itself runs in a
+            // container needs its data directory at the same path on the host
+            binds: spec.inputs.as_ref().map(|dir| vec![format!("{}:{}:ro", dir.display(), TASK_INPUTS_MOUNT)]),
+            device_requests: if spec.gpus == 0 {
+                None
+            } else {
+                Some(vec![DeviceRequest {
+                    driver: Some("nvidia".into()),
+                    count: Some(spec.gpus as i64),
+                    capabilities: Some(vec![vec!["gpu".into()]]),
+                    ..Default::default()
+                }])
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let name = container_name(task_id);
+    let container = docker
+        .create_container(Some(CreateContainerOptions { name: name.as_str() }), config)
+        .await?;
+
+    let mut result = wait_for_container(docker, &container.id, max_output).await;
+    if let Ok(output) = &mut result {
+        if output.exit_code == 0 {
+            output.files = copy_outputs(docker, &container.id, &spec.outputs).await;
+        }
+    }
+
+    if let Err(e) = docker
+        .remove_container(
+            &container.id,
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await
+    {
+        error!("Failed to remove container {}: {}", container.id, e);
+    }
+
+    result
+}
+
+// Copy each of `paths` out of a stopped container. Paths that don't exist or
+// aren't regular files are left out.
+async fn copy_outputs(docker: &Docker, container_id: &str, paths: &[String]) -> HashMap<String, Vec<u8>> {
+    let mut files = HashMap::new();
+    for path in paths {
+        // Docker sends the path as a tar archive
+        let archive = docker
+            .download_from_container(container_id, Some(DownloadFromContainerOptions { path: path.as_str() }))
+            .try_fold(Vec::new(), |mut archive, chunk| async move {
+                archive.extend_from_slice(&chunk);
+                Ok(archive)
+            })
+            .await;
+        match archive {
+            Ok(archive) => match first_tar_file(&archive) {
+                Some(data) => {
+                    files.insert(path.clone(), data);
+                }
+                None => debug!("Output {} of container {} is not a regular file", path, container_id),
+            },
+            Err(e) => debug!("Failed to copy output {} from container {}: {}", path, container_id, e),
+        }
+    }
+    files
+}
+
+// The contents of the first entry in a tar archive, if it's a regular file
+fn first_tar_file(archive: &[u8]) -> Option<Vec<u8>> {
+    let mut archive = tar::Archive::new(archive);
+    let mut entry = archive.entries().ok()?.next()?.ok()?;
+    if !entry.header().entry_type().is_file() {
+        return None;
+    }
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data).ok()?;
+    Some(data)
+}
+
+async fn wait_for_container(
+    docker: &Docker,
+    container_id: &str,
+    max_output: usize,
+) -> Result<TaskOutput, bollard::errors::Error> {
+    docker
+        .start_container(container_id, None::<StartContainerOptions<String>>)
+        .await?;
+
+    let mut wait = docker.wait_container(container_id, None::<WaitContainerOptions<String>>);
+    let exit_code = match wait.next().await {
+        Some(Ok(response)) => response.status_code,
+        // Bollard reports a non-zero exit as an error carrying the code
+        Some(Err(bollard::errors::Error::DockerContainerWaitError { code, .. })) => code,
+        Some(Err(e)) => return Err(e),
+        None => -1,
+    };
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut logs = docker.logs(
+        container_id,
+        Some(LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            ..Default::default()
+        }),
+    );
+    while let Some(output) = logs.next().await {
+        match output? {
+            LogOutput::StdOut { message } => append_capped(&mut stdout, &message, max_output),
+            LogOutput::StdErr { message } => append_capped(&mut stderr, &message, max_output),
+            _ => {}
+        }
+    }
+
+    Ok(TaskOutput {
+        exit_code,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        files: HashMap::new(),
+    })
+}
+
+/// Runs each task in a container on the local Docker daemon
+pub struct DockerExecutor {
+    docker: Docker,
+    max_output_bytes: usize,
+}
+
+impl DockerExecutor {
+    /// Use the local Docker daemon, keeping up to `max_output_bytes` each of
+    /// a task's stdout and stderr
+    pub fn connect(max_output_bytes: usize) -> Result<Self, Box<dyn Error>> {
+        Ok(DockerExecutor {
+            docker: Docker::connect_with_local_defaults()?,
+            max_output_bytes,
+        })
+    }
+}
+
+#[async_trait]
+impl TaskExecutor for DockerExecutor {
+    async fn execute(&self, request: &TaskRequest, inputs: Option<&Path>) -> TaskOutcome {
+        let spec = ContainerSpec {
+            image: request.docker_image.clone(),
+            command: request.command.clone(),
+            env: request.env.clone(),
+            working_dir: request.working_dir.clone(),
+            cpu_cores: request.cpu_cores,
+            memory_mb: request.memory_mb,
+            gpus: request.gpus,
+            inputs: inputs.map(Path::to_path_buf),
+            outputs: request.outputs.clone(),
+        };
+        run_container(&self.docker, &request.task_id, spec, self.max_output_bytes)
+            .await
+            .map_err(|e| format!("container error: {}", e))
+    }
+
+    async fn abort(&self, task_id: &str) {
+        force_remove_container(&self.docker, task_id).await;
+    }
+
+    async fn follow_logs(&self, task_id: &str) -> Option<BoxStream<'static, String>> {
+        let logs = self.docker.logs(
+            &container_name(task_id),
+            Some(LogsOptions::<String> {
+                follow: true,
+                stdout: true,
+                stderr: true,
+                ..Default::default()
+            }),
+        );
+        // Without a TTY Docker frames the output a line at a time, and the
+        // stream ends once the container exits or is removed
+        let lines = logs
+            .take_while(|output| futures::future::ready(output.is_ok()))
+            .filter_map(|output| {
+                futures::future::ready(match output {
+                    Ok(LogOutput::StdOut { message }) | Ok(LogOutput::StdErr { message }) => {
+                        Some(String::from_utf8_lossy(&message).trim_end_matches('\n').to_string())
+                    }
+                    _ => None,
+                })
+            });
+        Some(lines.boxed())
+    }
+}
+
+/// Pretends to run tasks, for tests and for demos without Docker. A task
+/// gets the outcome set for its task_id, else the default outcome, else a
+/// clean exit that prints the image and command it would have run.
+#[derive(Default)]
+pub struct MockExecutor {
+    default_outcome: Option<TaskOutcome>,
+    outcomes: HashMap<String, TaskOutcome>,
+    delay: Duration,
+}
+
+impl MockExecutor {
+    pub fn new() -> Self {
+        MockExecutor::default()
+    }
+
+    /// The outcome of every task without one of its own
+    pub fn default_outcome(mut self, outcome: TaskOutcome) -> Self {
+        self.default_outcome = Some(outcome);
+        self
+    }
+
+    /// The outcome of the task `task_id`
+    pub fn outcome(mut self, task_id: impl Into<String>, outcome: TaskOutcome) -> Self {
+        self.outcomes.insert(task_id.into(), outcome);
+        self
+    }
+
+    /// How long each task appears to run for
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+#[async_trait]
+impl TaskExecutor for MockExecutor {
+    async fn execute(&self, request: &TaskRequest, _inputs: Option<&Path>) -> TaskOutcome {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        match self.outcomes.get(&request.task_id).or(self.default_outcome.as_ref()) {
+            Some(outcome) => outcome.clone(),
+            None => Ok(TaskOutput {
+                exit_code: 0,
+                stdout: format!("dry run: {} {}\n", request.docker_image, request.command.join(" ")),
+                stderr: String::new(),
+                files: HashMap::new(),
+            }),
+        }
+    }
+}
+
+/// Notable things that happened on this node, kept for `GET /api/events`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeEvent {
+    PeerDiscovered { peer_id: String },
+    PeerExpired { peer_id: String },
+    TaskReceived { task_id: String, requester_id: String },
+    TaskStarted { task_id: String },
+    TaskCompleted { task_id: String },
+    TaskFailed { task_id: String, reason: String },
+    /// A task we submitted failed on every worker we tried
+    TaskAbandoned { task_id: String, attempts: u32, reason: String },
+    /// No majority of a redundant task's workers returned the same result
+    TaskVerificationFailed { task_id: String, results: u32, agreeing: u32 },
+    FileStored { file_id: String, size_bytes: u64 },
+    FileExpired { file_id: String },
+    ResourceOfferSeen { node_id: String },
+}
+
+/// A [`NodeEvent`] with its place in the event log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub event: NodeEvent,
+}
+
+// How many events `GET /api/events` can look back over
+const EVENT_LOG_CAPACITY: usize = 500;
+
+// Events buffered per WebSocket subscriber before it starts lagging
+const EVENT_STREAM_CAPACITY: usize = 128;
+
+// Bounded ring buffer of recent events with increasing sequence numbers,
+// also fanned out live to WebSocket subscribers
+struct EventLog {
+    next_seq: u64,
+    events: VecDeque<RecordedEvent>,
+    live: broadcast::Sender<RecordedEvent>,
+}
+
+impl EventLog {
+    fn new() -> Self {
+        EventLog {
+            next_seq: 0,
+            events: VecDeque::new(),
+            live: broadcast::channel(EVENT_STREAM_CAPACITY).0,
+        }
+    }
+
+    fn record(&mut self, event: NodeEvent) {
+        if self.events.len() == EVENT_LOG_CAPACITY {
+            self.events.pop_front();
+        }
+        self.next_seq += 1;
+        let recorded = RecordedEvent {
+            seq: self.next_seq,
+            timestamp_ms: unix_millis(),
+            event,
+        };
+        debug!(event = ?recorded.event, seq = recorded.seq, "Recorded event");
+        // No subscribers is fine
+        let _ = self.live.send(recorded.clone());
+        self.events.push_back(recorded);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<RecordedEvent> {
+        self.live.subscribe()
+    }
+
+    fn since(&self, seq: u64) -> Vec<RecordedEvent> {
+        self.events.iter().filter(|e| e.seq > seq).cloned().collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    since: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    #[serde(default)]
+    remote: bool,
+    cache: Option<bool>,
+}
+
+// Task history entries returned when no `?limit=` is given
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+// Forward live events to a WebSocket client as JSON text frames until either
+// side goes away. A client that falls behind skips the events it missed.
+async fn stream_events(socket: WebSocket, mut events: broadcast::Receiver<RecordedEvent>) {
+    let (mut sink, mut incoming) = socket.split();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let json = serde_json::to_string(&event).expect("Failed to serialize");
+                    if sink.send(Message::text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    info!("Event stream client lagged, skipped {} events", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = incoming.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => break,
+            },
+        }
+    }
+    let _ = sink.close().await;
+}
+
+// Lines of task output buffered per WebSocket client before it starts
+// missing some
+const LOG_STREAM_CAPACITY: usize = 256;
+
+// Forward a task's output to a WebSocket client as text frames until the task
+// exits or the client goes away. The output is read at its own pace, so a
+// client that falls behind is told how many lines it missed.
+async fn stream_task_logs(socket: WebSocket, mut logs: BoxStream<'static, String>) {
+    let (mut sink, mut incoming) = socket.split();
+    let (line_sender, mut lines) = mpsc::channel(LOG_STREAM_CAPACITY);
+    let reader = tokio::spawn(async move {
+        let mut skipped = 0u64;
+        while let Some(line) = logs.next().await {
+            if skipped > 0 {
+                match line_sender.try_send(format!("[{} lines skipped]", skipped)) {
+                    Ok(()) => skipped = 0,
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        skipped += 1;
+                        continue;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => break,
+                }
+            }
+            match line_sender.try_send(line) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => skipped += 1,
+                Err(mpsc::error::TrySendError::Closed(_)) => break,
+            }
+        }
+    });
+    loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Some(line) => {
+                    if sink.send(Message::text(line)).await.is_err() {
+                        break;
+                    }
+                }
+                // The task exited
+                None => break,
+            },
+            message = incoming.next() => match message {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => break,
+            },
+        }
+    }
+    reader.abort();
+    let _ = sink.close().await;
+}
+
+// Spread `interval` by ±20% so nodes started together don't announce in lockstep
+fn with_jitter(interval: Duration) -> Duration {
+    interval.mul_f64(0.8 + 0.4 * rand::random::<f64>())
+}
+
+// Re-dials of a lost bootstrap peer start this soon and back off to at most
+// the maximum, doubling after each failed attempt
+const REDIAL_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const REDIAL_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+// Ask the main loop to re-dial `peer` after roughly `delay`
+fn schedule_redial(redial: &mpsc::UnboundedSender<PeerId>, peer: PeerId, delay: Duration) {
+    let redial = redial.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(with_jitter(delay)).await;
+        let _ = redial.send(peer);
+    });
+}
+
+// How long a peer's resource offer stays valid without being refreshed
+const RESOURCE_OFFER_TTL: Duration = Duration::from_secs(180);
+
+// Latest resources advertised by a peer
+struct ResourceRecord {
+    cpu_cores: u8,
+    memory_mb: u64,
+    storage_gb: u32,
+    bandwidth_mbps: u32,
+    capabilities: Capabilities,
+    protocol_version: u16,
+    // Topics the node's offers arrived on
+    topics: HashSet<String>,
+    last_seen: Instant,
+}
+
+// A peer's latest TaskCensus. Censuses travel with resource offers, so
+// they go stale after RESOURCE_OFFER_TTL too.
+struct CensusRecord {
+    tasks: Vec<TaskCensusEntry>,
+    last_seen: Instant,
+}
+
+// What we've seen of a peer's behaviour, used to score it
+#[derive(Default)]
+struct PeerStats {
+    tasks_attempted: u32,
+    tasks_succeeded: u32,
+    tasks_failed: u32,
+    malformed_messages: u32,
+    // Messages in the current one-minute window
+    window_start: Option<Instant>,
+    window_messages: u32,
+    // Token bucket for inbound commands; None until the first message
+    tokens: Option<(f64, Instant)>,
+    // Messages dropped for exceeding the bucket since the last ban
+    rate_violations: u32,
+    banned_until: Option<Instant>,
+}
+
+impl PeerStats {
+    // Between 0 and 1: the task success rate, smoothed so a new peer starts
+    // at 0.5, less 0.1 per malformed message
+    fn score(&self) -> f64 {
+        let success_rate = (self.tasks_succeeded as f64 + 1.0) / (self.tasks_attempted as f64 + 2.0);
+        (success_rate - 0.1 * self.malformed_messages as f64).clamp(0.0, 1.0)
+    }
+}
+
+// Per-peer reputation. Peers scoring below the threshold, or sending more
+// than the allowed message rate, are ignored for a cool-down period and
+// scheduled last. Short bursts over `messages_per_sec` are dropped without a
+// ban until they add up to `max_rate_violations`.
+struct Reputation {
+    config: ReputationConfig,
+    peers: HashMap<String, PeerStats>,
+}
+
+impl Reputation {
+    fn new(config: ReputationConfig) -> Self {
+        Reputation {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    fn score(&self, peer: &str) -> f64 {
+        self.peers.get(peer).map_or(PeerStats::default().score(), PeerStats::score)
+    }
+
+    fn is_trusted(&self, peer: &str) -> bool {
+        self.score(peer) >= self.config.min_score
+    }
+
+    fn is_banned(&self, peer: &str) -> bool {
+        self.peers
+            .get(peer)
+            .and_then(|stats| stats.banned_until)
+            .map_or(false, |until| Instant::now() < until)
+    }
+
+    // Count a message from `peer` and say whether to handle it
+    fn record_message(&mut self, peer: &str) -> Admission {
+        if self.is_banned(peer) {
+            return Admission::Banned;
+        }
+        if self.config.trusted_peers.iter().any(|trusted| trusted == peer) {
+            return Admission::Accepted;
+        }
+        let config = &self.config;
+        let stats = self.peers.entry(peer.to_string()).or_default();
+        let now = Instant::now();
+        if stats.window_start.map_or(true, |start| now.duration_since(start) >= Duration::from_secs(60)) {
+            stats.window_start = Some(now);
+            stats.window_messages = 0;
+        }
+        stats.window_messages += 1;
+        if stats.window_messages > config.max_messages_per_min {
+            error!(peer_id = peer, "Peer {} sent more than {} messages in a minute", peer, config.max_messages_per_min);
+            self.ban(peer);
+            return Admission::Banned;
+        }
+
+        let burst = config.message_burst as f64;
+        let (tokens, refilled_at) = stats.tokens.unwrap_or((burst, now));
+        let tokens = (tokens + now.duration_since(refilled_at).as_secs_f64() * config.messages_per_sec).min(burst);
+        if tokens < 1.0 {
+            stats.tokens = Some((tokens, now));
+            stats.rate_violations += 1;
+            if stats.rate_violations == 1 {
+                warn!(peer_id = peer, "Rate limiting {}: over {} messages/sec", peer, config.messages_per_sec);
+            }
+            if stats.rate_violations >= config.max_rate_violations {
+                error!(peer_id = peer, "Peer {} kept exceeding its message rate", peer);
+                self.ban(peer);
+            }
+            return Admission::RateLimited;
+        }
+        stats.tokens = Some((tokens - 1.0, now));
+        Admission::Accepted
+    }
+
+    fn record_malformed(&mut self, peer: &str) {
+        self.peers.entry(peer.to_string()).or_default().malformed_messages += 1;
+        self.check(peer);
+    }
+
+    fn record_task(&mut self, peer: &str, success: bool) {
+        let stats = self.peers.entry(peer.to_string()).or_default();
+        stats.tasks_attempted += 1;
+        if success {
+            stats.tasks_succeeded += 1;
+        } else {
+            stats.tasks_failed += 1;
+        }
+        self.check(peer);
+    }
+
+    fn check(&mut self, peer: &str) {
+        if !self.is_trusted(peer) && !self.is_banned(peer) {
+            info!(peer_id = peer, "Peer {} fell below the reputation threshold", peer);
+            self.ban(peer);
+        }
+    }
+
+    fn ban(&mut self, peer: &str) {
+        let cooldown = Duration::from_secs(self.config.ban_secs);
+        let stats = self.peers.entry(peer.to_string()).or_default();
+        stats.banned_until = Some(Instant::now() + cooldown);
+        stats.rate_violations = 0;
+    }
+
+    fn stats_json(&self, peer: &str) -> serde_json::Value {
+        let stats = self.peers.get(peer);
+        serde_json::json!({
+            "score": self.score(peer),
+            "tasks_attempted": stats.map_or(0, |s| s.tasks_attempted),
+            "tasks_succeeded": stats.map_or(0, |s| s.tasks_succeeded),
+            "tasks_failed": stats.map_or(0, |s| s.tasks_failed),
+            "malformed_messages": stats.map_or(0, |s| s.malformed_messages),
+            "rate_violations": stats.map_or(0, |s| s.rate_violations),
+            "banned": self.is_banned(peer)
+        })
+    }
+}
+
+// What to do with an inbound message, from `Reputation::record_message`
+#[derive(Debug, PartialEq, Eq)]
+enum Admission {
+    Accepted,
+    // The peer is serving a ban
+    Banned,
+    // The peer is over its message rate; drop this one
+    RateLimited,
+}
+
+// A task executing on this node, with the handle used to cancel it
+struct RunningTask {
+    requester_id: String,
+    cancel: oneshot::Sender<()>,
+    // What it has reserved, for the console
+    cpu_cores: u8,
+    memory_mb: u32,
+    gpus: u8,
+}
+
+// Everything this node knows, shared by the swarm, command loop and API.
+// The durable parts are saved to state.json and restored on start.
+struct NodeState {
+    node_id: String,
+    // When this process started, for uptime on the clock that can't jump and
+    // as wall-clock time for display
+    started: Instant,
+    started_at: SystemTime,
+    // Whole CPU cores free for tasks, the same unit as `TaskRequest.cpu_cores`
+    available_cpu: u8,
+    available_memory: u64,
+    // Configured capacity offered to the network; operators can change it at
+    // runtime through `PUT /api/node/resources`
+    total_cpu: u8,
+    total_memory: u64,
+    // Storage in bytes
+    total_storage: u64,
+    // Held by running tasks, and by stored and incoming files
+    reserved_cpu: u8,
+    reserved_gpus: u8,
+    reserved_memory: u64,
+    reserved_storage: u64,
+    // Host platform and accelerators, detected at startup
+    capabilities: Capabilities,
+    // Latest host utilization from the resource sampler
+    cpu_usage_percent: f32,
+    idle_cpu_cores: u8,
+    free_memory_mb: u64,
+    available_bandwidth: u32,
+    peers: HashSet<String>,
+    tasks: Vec<String>,
+    stored_files: Vec<String>,
+    // Size in bytes of each stored file, including transfers still arriving
+    file_sizes: HashMap<String, u64>,
+    // Files we hold for other nodes, including transfers still arriving, and
+    // the most any one node may have us hold
+    peer_files: HashMap<String, PeerFile>,
+    max_storage_per_peer: Option<u64>,
+    // Status of tasks we submitted or ran, keyed by task_id
+    task_states: HashMap<String, TaskState>,
+    // Tasks we submitted that may still be retried on another worker
+    submitted_tasks: HashMap<String, SubmittedTask>,
+    // Submitters waiting for a task to finish, told its final state
+    task_waiters: HashMap<String, oneshot::Sender<TaskState>>,
+    // Tasks currently executing here, keyed by task_id
+    running_tasks: HashMap<String, RunningTask>,
+    // Results of tasks that recently finished here, re-sent if the same
+    // task_id arrives again instead of running it twice
+    finished_tasks: LruCache<String, FinishedTask>,
+    // Tasks that finished here, oldest first, for auditing
+    task_history: VecDeque<TaskRecord>,
+    // Set once shutdown starts so no new work is accepted
+    shutting_down: bool,
+    // Toggled by an operator for maintenance: running tasks finish, but no
+    // new tasks or files are accepted
+    draining: bool,
+    // Uploads collecting StorageOffers, keyed by file_id; each offering
+    // node_id is forwarded to the waiting upload
+    storage_offers: HashMap<String, mpsc::UnboundedSender<String>>,
+    // Tasks waiting on an input being fetched from a peer, keyed by file_id
+    input_fetches: HashMap<String, Vec<oneshot::Sender<Result<(), String>>>>,
+    // Files other nodes are pushing to us, keyed by file_id
+    incoming_transfers: HashMap<String, IncomingTransfer>,
+    // Remote nodes we've pushed a copy of each local file to
+    file_replicas: HashMap<String, HashSet<String>>,
+    // Files that are deleted once their TTL runs out, keyed by file_id
+    file_ttls: HashMap<String, FileTtl>,
+    // Latest ping round-trip time to each connected peer
+    peer_rtts: HashMap<String, Duration>,
+    // Software and addresses each connected peer reported via Identify
+    peer_identities: HashMap<String, PeerIdentity>,
+    // Remote addresses and connection time of each connected peer
+    peer_connections: HashMap<String, PeerConnection>,
+    peer_discovery: HashMap<String, Discovery>,
+    // When we last heard from each peer, by ping, message or discovery
+    peer_last_seen: HashMap<String, Instant>,
+    // Where peers can be reached, kept across restarts
+    peer_store: PeerStore,
+    // Where we can be dialled right now, as `<addr>/p2p/<our peer id>`
+    listen_addresses: Vec<String>,
+    // Also held by the swarm, which admits messages without the node lock
+    reputation: Arc<Mutex<Reputation>>,
+    // Resource offers from other nodes, keyed by node_id
+    network_resources: HashMap<String, ResourceRecord>,
+    // The tasks each other node last said it was running
+    cluster_tasks: HashMap<String, CensusRecord>,
+    // Recent activity for dashboards
+    events: EventLog,
+    // Set on every mutation of persisted fields, cleared once written to disk
+    dirty: bool,
+}
+
+// The part of the node state that survives a restart. CPU, memory and bandwidth
+// are capacities re-read from the configuration, but storage is a ledger of
+// files we hold and must be restored.
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    #[serde(default)]
+    reserved_storage_bytes: u64,
+    // Free storage in GB, written instead of `reserved_storage_bytes` by older versions
+    #[serde(default, skip_serializing)]
+    available_storage: Option<u32>,
+    stored_files: Vec<String>,
+    tasks: Vec<String>,
+    #[serde(default)]
+    file_replicas: HashMap<String, HashSet<String>>,
+    #[serde(default)]
+    file_ttls: HashMap<String, FileTtl>,
+    #[serde(default)]
+    peer_files: HashMap<String, PeerFile>,
+    #[serde(default)]
+    file_sizes: HashMap<String, u64>,
+    #[serde(default)]
+    task_history: VecDeque<TaskRecord>,
+}
+
+// A file stored at another node's request, counted against its quota
+#[derive(Clone, Serialize, Deserialize)]
+struct PeerFile {
+    owner: String,
+    size_bytes: u64,
+}
+
+// When a stored file was written and how long it may be kept
+#[derive(Clone, Serialize, Deserialize)]
+struct FileTtl {
+    stored_at: u64,
+    ttl_secs: u64,
+    // Storage to give back once it expires
+    size_bytes: u64,
+}
+
+impl FileTtl {
+    fn remaining_secs(&self, now: u64) -> u64 {
+        (self.stored_at + self.ttl_secs).saturating_sub(now)
+    }
+}
+
+impl NodeState {
+    fn new(node_id: String, config: &NodeConfig, total_memory: u64) -> Self {
+        let total_cpu = cpu_cores_for_percent(config.resources.cpu_percent);
+        NodeState {
+            node_id,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            available_cpu: total_cpu,
+            available_memory: total_memory,
+            total_cpu,
+            total_memory,
+            total_storage: config.resources.storage_gb as u64 * GIB,
+            reserved_cpu: 0,
+            reserved_gpus: 0,
+            reserved_memory: 0,
+            reserved_storage: 0,
+            capabilities: Capabilities::detect(&config.security.image_allowlist),
+            cpu_usage_percent: 0.0,
+            idle_cpu_cores: total_cpu,
+            free_memory_mb: total_memory,
+            available_bandwidth: config.resources.bandwidth_mbps,
+            peers: HashSet::new(),
+            tasks: Vec::new(),
+            stored_files: Vec::new(),
+            file_sizes: HashMap::new(),
+            peer_files: HashMap::new(),
+            max_storage_per_peer: config.resources.max_storage_per_peer_gb.map(|gb| gb as u64 * GIB),
+            task_states: HashMap::new(),
+            submitted_tasks: HashMap::new(),
+            task_waiters: HashMap::new(),
+            running_tasks: HashMap::new(),
+            finished_tasks: LruCache::new(NonZeroUsize::new(FINISHED_TASK_CACHE_SIZE).unwrap()),
+            task_history: VecDeque::new(),
+            shutting_down: false,
+            draining: false,
+            storage_offers: HashMap::new(),
+            input_fetches: HashMap::new(),
+            incoming_transfers: HashMap::new(),
+            file_replicas: HashMap::new(),
+            file_ttls: HashMap::new(),
+            peer_rtts: HashMap::new(),
+            peer_identities: HashMap::new(),
+            peer_connections: HashMap::new(),
+            peer_discovery: HashMap::new(),
+            peer_last_seen: HashMap::new(),
+            peer_store: PeerStore::default(),
+            listen_addresses: Vec::new(),
+            reputation: Arc::new(Mutex::new(Reputation::new(config.reputation.clone()))),
+            network_resources: HashMap::new(),
+            cluster_tasks: HashMap::new(),
+            events: EventLog::new(),
+            dirty: false,
+        }
+    }
+
+    fn set_task_state(&mut self, task_id: &str, status: TaskStatus, node_id: Option<String>) {
+        let state = self
+            .task_states
+            .entry(task_id.to_string())
+            .or_insert(TaskState { status, node_id: None, attempts: 0, quorum: None, output_files: HashMap::new() });
+        state.status = status;
+        if node_id.is_some() {
+            state.node_id = node_id;
+        }
+        if status.is_terminal() {
+            if let Some(waiter) = self.task_waiters.remove(task_id) {
+                let _ = waiter.send(state.clone());
+            }
+        }
+    }
+
+    // Decide whether to resubmit a task after `worker` turned it down or failed it
+    fn retry_submitted(&mut self, task_id: &str, worker: &str, max_retries: u32) -> Retry {
+        let submitted = match self.submitted_tasks.get(task_id) {
+            Some(submitted) if submitted.tried.contains(worker) => submitted,
+            _ => return Retry::Ignore,
+        };
+        // Redundant copies dispatched up front aren't retries
+        let copies = submitted.request.redundancy.max(1) as u32;
+        if submitted.attempts > max_retries + copies - 1 {
+            let attempts = submitted.attempts;
+            self.submitted_tasks.remove(task_id);
+            return Retry::GiveUp { attempts };
+        }
+        Retry::Again(submitted.request.clone())
+    }
+
+    // Count `worker`'s successful result toward the quorum of a redundant
+    // task we submitted, recording where the vote stands in its state
+    fn record_quorum_result(&mut self, task_id: &str, worker: &str, digest: String) -> Quorum {
+        // Results still running workers send after the vote change nothing
+        if self.quorum_decided(task_id) {
+            return Quorum::Pending;
+        }
+        let redundancy = match self.submitted_tasks.get(task_id) {
+            Some(submitted) if submitted.request.redundancy > 1 => submitted.request.redundancy,
+            _ => return Quorum::Agreed,
+        };
+        let state = self
+            .task_states
+            .entry(task_id.to_string())
+            .or_insert(TaskState {
+                status: TaskStatus::Running,
+                node_id: None,
+                attempts: 0,
+                quorum: None,
+                output_files: HashMap::new(),
+            });
+        let quorum = state.quorum.get_or_insert_with(|| QuorumOutcome {
+            redundancy,
+            required: redundancy / 2 + 1,
+            results: HashMap::new(),
+            verified: None,
+        });
+        quorum.results.insert(worker.to_string(), digest.clone());
+
+        let agreeing = quorum.results.values().filter(|d| **d == digest).count() as u32;
+        let most_agreeing = quorum
+            .results
+            .values()
+            .map(|d| quorum.results.values().filter(|other| *other == d).count() as u32)
+            .max()
+            .unwrap_or(0);
+        if agreeing >= quorum.required as u32 {
+            quorum.verified = Some(true);
+            Quorum::Agreed
+        } else if quorum.results.len() >= redundancy as usize {
+            quorum.verified = Some(false);
+            Quorum::Disagreed { results: quorum.results.len() as u32, agreeing: most_agreeing }
+        } else {
+            Quorum::Pending
+        }
+    }
+
+    fn quorum_decided(&self, task_id: &str) -> bool {
+        self.task_states
+            .get(task_id)
+            .and_then(|state| state.quorum.as_ref())
+            .map_or(false, |quorum| quorum.verified.is_some())
+    }
+
+    // Tell the tasks waiting on a fetched input whether it arrived
+    fn finish_fetch(&mut self, file_id: &str, result: Result<(), String>) {
+        for waiter in self.input_fetches.remove(file_id).unwrap_or_default() {
+            let _ = waiter.send(result.clone());
+        }
+    }
+
+    // Drop an incoming transfer and give back the storage reserved for it
+    fn abort_transfer(&mut self, file_id: &str) {
+        self.finish_fetch(file_id, Err("the transfer failed or stalled".into()));
+        if let Some(transfer) = self.incoming_transfers.remove(file_id) {
+            self.release_storage(transfer.size_bytes);
+            self.stored_files.retain(|f| f != file_id);
+            self.file_sizes.remove(file_id);
+            self.peer_files.remove(file_id);
+            self.dirty = true;
+        }
+    }
+
+    // Drop a file we only held a copy of, giving back its storage. The
+    // caller deletes its contents.
+    fn forget_file(&mut self, file_id: &str) {
+        if let Some(size_bytes) = self.file_sizes.remove(file_id) {
+            self.release_storage(size_bytes);
+        }
+        self.stored_files.retain(|f| f != file_id);
+        self.file_ttls.remove(file_id);
+        self.peer_files.remove(file_id);
+        self.dirty = true;
+    }
+
+    // Start the clock on a file we just stored, if it has a TTL
+    fn set_file_ttl(&mut self, file_id: &str, size_bytes: u64, ttl_secs: Option<u64>) {
+        if let Some(ttl_secs) = ttl_secs {
+            self.file_ttls.insert(file_id.to_string(), FileTtl {
+                stored_at: unix_secs(),
+                ttl_secs,
+                size_bytes,
+            });
+            self.dirty = true;
+        }
+    }
+
+    // Forget every file whose TTL has run out and give back its storage,
+    // returning the ids so their contents can be deleted
+    fn take_expired_files(&mut self, now: u64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .file_ttls
+            .iter()
+            .filter(|(_, ttl)| ttl.remaining_secs(now) == 0)
+            .map(|(file_id, _)| file_id.clone())
+            .collect();
+        for file_id in &expired {
+            if let Some(ttl) = self.file_ttls.remove(file_id) {
+                self.release_storage(ttl.size_bytes);
+            }
+            self.stored_files.retain(|f| f != file_id);
+            self.file_sizes.remove(file_id);
+            self.file_replicas.remove(file_id);
+            self.peer_files.remove(file_id);
+            self.events.record(NodeEvent::FileExpired { file_id: file_id.clone() });
+        }
+        expired
+    }
+
+    // Advertise what's left of our capacity after reservations, but never
+    // more than the host actually has idle right now
+    fn refresh_available(&mut self) {
+        self.available_cpu = self
+            .total_cpu
+            .saturating_sub(self.reserved_cpu)
+            .min(self.idle_cpu_cores);
+        self.available_memory = self
+            .total_memory
+            .saturating_sub(self.reserved_memory)
+            .min(self.free_memory_mb);
+    }
+
+    // Apply new limits from an operator, never dropping below what's already
+    // reserved so running tasks and stored files stay accounted for
+    fn set_limits(&mut self, limits: &ResourceLimits) {
+        if let Some(cpu_percent) = limits.cpu_percent {
+            self.total_cpu = cpu_cores_for_percent(cpu_percent).max(self.reserved_cpu);
+        }
+        if let Some(storage_gb) = limits.storage_gb {
+            self.total_storage = (storage_gb as u64 * GIB).max(self.reserved_storage);
+        }
+        if let Some(bandwidth_mbps) = limits.bandwidth_mbps {
+            self.available_bandwidth = bandwidth_mbps;
+        }
+        self.refresh_available();
+    }
+
+    // What we announce to the network; nothing is free while draining
+    fn resource_offer(&self) -> OpenSkyCommand {
+        let free = if self.draining {
+            Resources { cpu_cores: 0, memory_mb: 0, storage_bytes: 0 }
+        } else {
+            self.available()
+        };
+        OpenSkyCommand::ResourceOffer {
+            cpu_cores: free.cpu_cores,
+            memory_mb: free.memory_mb,
+            storage_gb: (free.storage_bytes / GIB) as u32,
+            bandwidth_mbps: if self.draining { 0 } else { self.available_bandwidth },
+            node_id: self.node_id.clone(),
+            arch: self.capabilities.arch.clone(),
+            os: self.capabilities.os.clone(),
+            // Only the GPUs no task holds
+            gpus: if self.draining {
+                Vec::new()
+            } else {
+                self.capabilities.gpus.iter().skip(self.reserved_gpus as usize).cloned().collect()
+            },
+            image_allowlist: self.capabilities.image_allowlist.clone(),
+            protocol_version: OPENSKY_PROTOCOL_VERSION,
+        }
+    }
+
+    fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    fn started_at_rfc3339(&self) -> String {
+        humantime::format_rfc3339_seconds(self.started_at).to_string()
+    }
+
+    // Record a new connection to `peer` at `address`, and how we found the
+    // peer if this is the first we've heard of it
+    fn connection_opened(&mut self, peer: &str, address: String, via: Discovery) {
+        self.peers.insert(peer.to_string());
+        self.peer_last_seen.insert(peer.to_string(), Instant::now());
+        self.peer_discovery.entry(peer.to_string()).or_insert(via);
+        let connection = self.peer_connections.entry(peer.to_string()).or_insert_with(|| PeerConnection {
+            addresses: Vec::new(),
+            connected_since: SystemTime::now(),
+        });
+        if !connection.addresses.contains(&address) {
+            connection.addresses.push(address);
+        }
+    }
+
+    // Forget one connection to `peer`; with none left, forget the peer and
+    // its offers, which it can no longer be asked to honour
+    fn connection_closed(&mut self, peer: &str, address: &str, remaining: u32) {
+        if remaining > 0 {
+            if let Some(connection) = self.peer_connections.get_mut(peer) {
+                connection.addresses.retain(|a| a != address);
+            }
+            return;
+        }
+        self.peers.remove(peer);
+        self.peer_rtts.remove(peer);
+        self.peer_identities.remove(peer);
+        self.peer_connections.remove(peer);
+        self.peer_discovery.remove(peer);
+        self.network_resources.remove(peer);
+        self.cluster_tasks.remove(peer);
+    }
+
+    // Drop peers we have no connection to and haven't heard from within
+    // `timeout`, such as ones that crashed before mDNS noticed. Returns them
+    // along with the files whose copies they held, to re-replicate.
+    fn prune_silent_peers(&mut self, timeout: Duration) -> (Vec<String>, Vec<String>) {
+        let silent: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|peer| !self.peer_connections.contains_key(*peer))
+            .filter(|peer| self.peer_last_seen.get(*peer).map_or(true, |seen| seen.elapsed() >= timeout))
+            .cloned()
+            .collect();
+        let mut lost_copies = Vec::new();
+        for peer in &silent {
+            self.connection_closed(peer, "", 0);
+            self.peer_last_seen.remove(peer);
+            self.events.record(NodeEvent::PeerExpired { peer_id: peer.clone() });
+            for (file_id, replicas) in self.file_replicas.iter_mut() {
+                if replicas.remove(peer) {
+                    lost_copies.push(file_id.clone());
+                }
+            }
+        }
+        (silent, lost_copies)
+    }
+
+    // The tasks running here, as reported in our TaskCensus
+    fn local_tasks(&self) -> Vec<TaskCensusEntry> {
+        self.tasks
+            .iter()
+            .map(|task_id| TaskCensusEntry {
+                task_id: task_id.clone(),
+                status: self.task_states.get(task_id).map_or(TaskStatus::Running, |state| state.status),
+            })
+            .collect()
+    }
+
+    fn task_census(&self) -> OpenSkyCommand {
+        OpenSkyCommand::TaskCensus {
+            node_id: self.node_id.clone(),
+            tasks: self.local_tasks(),
+        }
+    }
+
+    fn available_storage(&self) -> u64 {
+        self.total_storage.saturating_sub(self.reserved_storage)
+    }
+
+    // Storage held for files `peer` asked us to keep
+    fn peer_storage_used(&self, peer: &str) -> u64 {
+        self.peer_files
+            .values()
+            .filter(|file| file.owner == peer)
+            .map(|file| file.size_bytes)
+            .sum()
+    }
+
+    // Hold `size_bytes` for a file, if that much is free
+    fn reserve_storage(&mut self, size_bytes: u64) -> bool {
+        if self.available_storage() < size_bytes {
+            return false;
+        }
+        self.reserved_storage += size_bytes;
+        self.dirty = true;
+        true
+    }
+
+    fn release_storage(&mut self, size_bytes: u64) {
+        self.reserved_storage = self.reserved_storage.saturating_sub(size_bytes);
+        self.dirty = true;
+    }
+
+    fn free_gpus(&self) -> usize {
+        self.capabilities.gpus.len().saturating_sub(self.reserved_gpus as usize)
+    }
+
+    // Whether this node could run `request` itself right now
+    fn fits_locally(&self, request: &TaskRequest) -> bool {
+        !self.shutting_down
+            && !self.draining
+            && request
+                .platform
+                .as_deref()
+                .map_or(true, |platform| self.capabilities.supports(platform))
+            && self.capabilities.runs_image(&request.docker_image)
+            && self.available_cpu >= request.cpu_cores
+            && self.available_memory >= request.memory_mb as u64
+            && self.free_gpus() >= request.gpus as usize
+    }
+
+    fn release_task(&mut self, cpu_cores: u8, memory_mb: u32, gpus: u8) {
+        self.reserved_cpu = self.reserved_cpu.saturating_sub(cpu_cores);
+        self.reserved_gpus = self.reserved_gpus.saturating_sub(gpus);
+        self.reserved_memory = self.reserved_memory.saturating_sub(memory_mb as u64);
+        self.refresh_available();
+    }
+
+    fn available(&self) -> Resources {
+        Resources {
+            cpu_cores: self.available_cpu,
+            memory_mb: self.available_memory,
+            storage_bytes: self.available_storage(),
+        }
+    }
+
+    fn total(&self) -> Resources {
+        Resources {
+            cpu_cores: self.total_cpu,
+            memory_mb: self.total_memory,
+            storage_bytes: self.total_storage,
+        }
+    }
+
+    fn reserved(&self) -> Resources {
+        Resources {
+            cpu_cores: self.reserved_cpu,
+            memory_mb: self.reserved_memory,
+            storage_bytes: self.reserved_storage,
+        }
+    }
+
+    fn record_task_history(&mut self, record: TaskRecord) {
+        if self.task_history.len() == TASK_HISTORY_CAPACITY {
+            self.task_history.pop_front();
+        }
+        self.task_history.push_back(record);
+        self.dirty = true;
+    }
+
+    // The `limit` most recently finished tasks, newest first
+    fn recent_task_history(&self, limit: usize) -> Vec<TaskRecord> {
+        self.task_history.iter().rev().take(limit).cloned().collect()
+    }
+
+    fn persisted_state(&self) -> PersistedState {
+        PersistedState {
+            reserved_storage_bytes: self.reserved_storage,
+            available_storage: None,
+            stored_files: self.stored_files.clone(),
+            tasks: self.tasks.clone(),
+            file_replicas: self.file_replicas.clone(),
+            file_ttls: self.file_ttls.clone(),
+            peer_files: self.peer_files.clone(),
+            file_sizes: self.file_sizes.clone(),
+            task_history: self.task_history.clone(),
+        }
+    }
+
+    fn restore(&mut self, state: PersistedState) {
+        self.reserved_storage = match state.available_storage {
+            Some(available_gb) => self.total_storage.saturating_sub(available_gb as u64 * GIB),
+            None => state.reserved_storage_bytes,
+        };
+        self.stored_files = state.stored_files;
+        self.file_replicas = state.file_replicas;
+        self.file_ttls = state.file_ttls;
+        self.peer_files = state.peer_files;
+        self.file_sizes = state.file_sizes;
+        self.task_history = state.task_history;
+        while self.task_history.len() > TASK_HISTORY_CAPACITY {
+            self.task_history.pop_front();
+        }
+        // Containers don't survive a restart, so these tasks were cut short
+        for task_id in state.tasks {
+            error!("Task {} was interrupted by a restart", task_id);
+        }
+    }
+}
+
+// Load previously persisted state, treating a missing file as a fresh start
+fn load_state(path: &Path) -> Result<Option<PersistedState>, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Write state to a temporary file first so a crash never leaves a truncated file
+fn save_state(path: &Path, state: &PersistedState) -> std::io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+    fs::rename(tmp, path)
+}
+
+// Lock-free flags behind the `/health` and `/ready` probes. A Kubernetes
+// deployment would typically use:
+//
+//   livenessProbe:  { httpGet: { path: /health, port: 8080 }, periodSeconds: 10 }
+//   readinessProbe: { httpGet: { path: /ready, port: 8080 }, periodSeconds: 5 }
+//
+// Readiness can take up to one announcement interval after startup.
+#[derive(Default)]
+struct Probes {
+    // The swarm reported at least one listen address
+    listening: AtomicBool,
+    // Our first resource offer has been queued for publishing
+    announced: AtomicBool,
+    // Unix seconds of the main event loop's last heartbeat
+    heartbeat: AtomicU64,
+    // Mirrors `NodeState::draining` so the probe never waits on the node lock
+    draining: AtomicBool,
+}
+
+// The main loop beats every few seconds; a longer silence means it's stuck
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const HEARTBEAT_TIMEOUT_SECS: u64 = 30;
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl Probes {
+    fn is_alive(&self) -> bool {
+        unix_secs().saturating_sub(self.heartbeat.load(Ordering::Relaxed)) < HEARTBEAT_TIMEOUT_SECS
+    }
+
+    fn is_ready(&self) -> bool {
+        self.is_alive()
+            && self.listening.load(Ordering::Relaxed)
+            && self.announced.load(Ordering::Relaxed)
+            && !self.draining.load(Ordering::Relaxed)
+    }
+}
+
+// Flip drain mode on or off, returning whether the node is now draining
+async fn toggle_drain(node: &RwLock<NodeState>, probes: &Probes) -> bool {
+    let mut node = node.write().await;
+    node.draining = !node.draining;
+    probes.draining.store(node.draining, Ordering::Relaxed);
+    if node.draining {
+        info!("Draining: running tasks will finish, new work is rejected");
+    } else {
+        info!("No longer draining; accepting new work");
+    }
+    node.draining
+}
+
+fn probe_status(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+// Prometheus metrics served at `/metrics`. Gauges mirroring node state are
+// refreshed from it under the node lock at scrape time; counters and the
+// duration histogram are updated by the command loop as tasks finish.
+struct Metrics {
+    registry: Registry,
+    tasks_total: IntCounterVec,
+    tasks_active: IntGauge,
+    peers_connected: IntGauge,
+    storage_available_bytes: IntGauge,
+    files_stored: IntGauge,
+    task_duration: Histogram,
+    invalid_messages: IntCounter,
+    oversized_messages: IntCounter,
+    rate_limited_messages: IntCounter,
+    connection_errors: IntCounterVec,
+    start_time: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let tasks_total = IntCounterVec::new(
+            Opts::new("opensky_tasks_total", "Tasks executed by this node"),
+            &["result"],
+        )?;
+        let tasks_active = IntGauge::new("opensky_tasks_active", "Tasks currently running")?;
+        let peers_connected = IntGauge::new("opensky_peers_connected", "Connected peers")?;
+        let storage_available_bytes =
+            IntGauge::new("opensky_storage_available_bytes", "Storage offered to the network")?;
+        let files_stored = IntGauge::new("opensky_files_stored", "Files held by this node")?;
+        let start_time = IntGauge::new("opensky_start_time_seconds", "Unix time the node started")?;
+        let task_duration = Histogram::with_opts(
+            HistogramOpts::new("opensky_task_duration_seconds", "Task execution time")
+                .buckets(vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+        )?;
+        let invalid_messages = IntCounter::new(
+            "opensky_invalid_messages_total",
+            "Gossip messages dropped because they couldn't be decoded",
+        )?;
+        let oversized_messages = IntCounter::new(
+            "opensky_oversized_messages_total",
+            "Gossip messages dropped for exceeding OPENSKY_MAX_MESSAGE_BYTES",
+        )?;
+        let rate_limited_messages = IntCounter::new(
+            "opensky_rate_limited_messages_total",
+            "Inbound commands dropped because their peer exceeded OPENSKY_MESSAGES_PER_SEC",
+        )?;
+        let connection_errors = IntCounterVec::new(
+            Opts::new("opensky_connection_errors_total", "Connections that failed to be established"),
+            &["direction"],
+        )?;
+
+        registry.register(Box::new(tasks_total.clone()))?;
+        registry.register(Box::new(tasks_active.clone()))?;
+        registry.register(Box::new(peers_connected.clone()))?;
+        registry.register(Box::new(storage_available_bytes.clone()))?;
+        registry.register(Box::new(files_stored.clone()))?;
+        registry.register(Box::new(task_duration.clone()))?;
+        registry.register(Box::new(invalid_messages.clone()))?;
+        registry.register(Box::new(oversized_messages.clone()))?;
+        registry.register(Box::new(rate_limited_messages.clone()))?;
+        registry.register(Box::new(connection_errors.clone()))?;
+        registry.register(Box::new(start_time.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            tasks_total,
+            tasks_active,
+            peers_connected,
+            storage_available_bytes,
+            files_stored,
+            task_duration,
+            invalid_messages,
+            oversized_messages,
+            rate_limited_messages,
+            connection_errors,
+            start_time,
+        })
+    }
+
+    fn render(&self, node: &NodeState) -> String {
+        self.tasks_active.set(node.tasks.len() as i64);
+        self.peers_connected.set(node.peers.len() as i64);
+        self.storage_available_bytes.set(node.available_storage() as i64);
+        self.files_stored.set(node.stored_files.len() as i64);
+        self.start_time.set(node.started_at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64));
+
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+// Free space on the filesystem holding `path`, from the disk with the longest
+// mount point containing it. None if no mounted disk matches.
+fn disk_free_bytes(path: &Path) -> Option<u64> {
+    let mut system = System::new();
+    system.refresh_disks_list();
+    system
+        .disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+// Whether the disk can take `size_bytes` more on top of the transfers
+// already in flight; unknown free space doesn't block storage
+fn disk_has_room(node: &NodeState, disk_free: Option<u64>, size_bytes: u64) -> bool {
+    let incoming: u64 = node.incoming_transfers.values().map(|t| t.size_bytes).sum();
+    disk_free.map_or(true, |free| free >= incoming + size_bytes)
+}
+
+// Largest file accepted through `POST /api/files`
+const MAX_UPLOAD_BYTES: u64 = 64 * 1024 * 1024;
+
+// Body of `PUT /api/node/resources`; fields left out keep their current value
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResourceLimits {
+    #[serde(default)]
+    cpu_percent: Option<u8>,
+    #[serde(default)]
+    storage_gb: Option<u32>,
+    #[serde(default)]
+    bandwidth_mbps: Option<u32>,
+}
+
+// CPU is accounted in whole cores: OPENSKY_MAX_CPU_PERCENT of the host's
+// cores are offered to the network, rounded down but never less than one
+fn cpu_cores_for_percent(percent: u8) -> u8 {
+    let host_cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let cores = host_cores * percent.min(100) as usize / 100;
+    cores.clamp(1, u8::MAX as usize) as u8
+}
+
+// Storage is accounted in bytes; configuration and resource offers use whole GiB
+const GIB: u64 = 1024 * 1024 * 1024;
+
+fn format_gb(bytes: u64) -> String {
+    format!("{:.2} GB", bytes as f64 / GIB as f64)
+}
+
+// Files are addressed by the hex SHA-256 of their content
+fn content_id(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn is_content_id(file_id: &str) -> bool {
+    file_id.len() == 64 && file_id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+// Hash a stored file without reading it into memory at once
+async fn file_digest(path: &Path) -> std::io::Result<String> {
+    reader_digest(tokio::fs::File::open(path).await?).await
+}
+
+async fn reader_digest(mut reader: impl AsyncRead + Unpin) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Where the contents of stored files are kept, by file id. Chosen by
+/// [`StorageConfig::backend`]; transfers are staged on local disk either way.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, file_id: &str, data: Vec<u8>) -> std::io::Result<()>;
+
+    /// The file's contents, or `None` if it isn't stored
+    async fn get(&self, file_id: &str) -> std::io::Result<Option<Vec<u8>>>;
+
+    /// The file's size and a reader over its contents, or `None` if it isn't
+    /// stored. Nothing is buffered up front, so large files can be streamed.
+    async fn get_reader(&self, file_id: &str) -> std::io::Result<Option<(u64, BlobReader)>>;
+
+    /// Move the file at `path` into the store, without reading it into memory
+    async fn put_file(&self, file_id: &str, path: &Path) -> std::io::Result<()>;
+
+    /// Deleting a file that isn't stored is not an error
+    async fn delete(&self, file_id: &str) -> std::io::Result<()>;
+
+    async fn exists(&self, file_id: &str) -> std::io::Result<bool>;
+}
+
+/// A stored file's contents, read as they're needed
+pub type BlobReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Keeps each file as `<dir>/<file_id>`
+pub struct LocalFsStore {
+    dir: PathBuf,
+}
+
+impl LocalFsStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalFsStore { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalFsStore {
+    async fn put(&self, file_id: &str, data: Vec<u8>) -> std::io::Result<()> {
+        // Write aside and rename, so a crash never leaves a partial file under its id
+        let tmp = self.dir.join(format!("{}.tmp", file_id));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(tmp, self.dir.join(file_id)).await
+    }
+
+    async fn get(&self, file_id: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.dir.join(file_id)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_reader(&self, file_id: &str) -> std::io::Result<Option<(u64, BlobReader)>> {
+        match tokio::fs::File::open(self.dir.join(file_id)).await {
+            Ok(file) => {
+                let size = file.metadata().await?.len();
+                Ok(Some((size, Box::new(file))))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn put_file(&self, file_id: &str, path: &Path) -> std::io::Result<()> {
+        let target = self.dir.join(file_id);
+        if tokio::fs::rename(path, &target).await.is_ok() {
+            return Ok(());
+        }
+        // Across filesystems, copy aside and rename as `put` does
+        let tmp = self.dir.join(format!("{}.tmp", file_id));
+        tokio::fs::copy(path, &tmp).await?;
+        tokio::fs::rename(tmp, target).await?;
+        tokio::fs::remove_file(path).await
+    }
+
+    async fn delete(&self, file_id: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.dir.join(file_id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, file_id: &str) -> std::io::Result<bool> {
+        match tokio::fs::metadata(self.dir.join(file_id)).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Keeps each file as the object `<file_id>` in an S3-compatible bucket
+pub struct S3Store {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+impl S3Store {
+    /// A store for `config.s3_bucket`. Credentials not set in `config` come
+    /// from the usual AWS environment variables and profiles.
+    pub async fn connect(config: &StorageConfig) -> Result<Self, Box<dyn Error>> {
+        let bucket = config
+            .s3_bucket
+            .clone()
+            .ok_or("OPENSKY_S3_BUCKET must be set to store files in S3")?;
+        let mut loader = aws_config::from_env();
+        if let Some(region) = &config.s3_region {
+            loader = loader.region(aws_sdk_s3::config::Region::new(region.clone()));
+        }
+        match (&config.s3_access_key_id, &config.s3_secret_access_key) {
+            (Some(key_id), Some(secret)) => {
+                let credentials = aws_sdk_s3::config::Credentials::new(key_id, secret, None, None, "opensky");
+                loader = loader.credentials_provider(credentials);
+            }
+            (None, None) => {}
+            _ => return Err("OPENSKY_S3_ACCESS_KEY_ID and OPENSKY_S3_SECRET_ACCESS_KEY must be set together".into()),
+        }
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&loader.load().await);
+        // Self-hosted stores such as MinIO rarely support virtual-hosted buckets
+        if let Some(endpoint) = &config.s3_endpoint {
+            s3_config = s3_config.endpoint_url(endpoint).force_path_style(true);
+        }
+        Ok(S3Store {
+            client: aws_sdk_s3::Client::from_conf(s3_config.build()),
+            bucket,
+        })
+    }
+}
+
+fn s3_error(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, format!("S3 request failed: {}", e))
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, file_id: &str, data: Vec<u8>) -> std::io::Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(file_id)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn get(&self, file_id: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match self.client.get_object().bucket(&self.bucket).key(file_id).send().await {
+            Ok(object) => {
+                let data = object.body.collect().await.map_err(s3_error)?;
+                Ok(Some(data.into_bytes().to_vec()))
+            }
+            Err(e) => match e.into_service_error() {
+                e if e.is_no_such_key() => Ok(None),
+                e => Err(s3_error(e)),
+            },
+        }
+    }
+
+    async fn get_reader(&self, file_id: &str) -> std::io::Result<Option<(u64, BlobReader)>> {
+        match self.client.get_object().bucket(&self.bucket).key(file_id).send().await {
+            Ok(object) => {
+                let size = object.content_length().map_or(0, |len| len.max(0) as u64);
+                Ok(Some((size, Box::new(object.body.into_async_read()))))
+            }
+            Err(e) => match e.into_service_error() {
+                e if e.is_no_such_key() => Ok(None),
+                e => Err(s3_error(e)),
+            },
+        }
+    }
+
+    async fn put_file(&self, file_id: &str, path: &Path) -> std::io::Result<()> {
+        let body = ByteStream::from_path(path).await.map_err(s3_error)?;
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(file_id)
+            .body(body)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        tokio::fs::remove_file(path).await
+    }
+
+    async fn delete(&self, file_id: &str) -> std::io::Result<()> {
+        // S3 deletes succeed whether or not the object exists
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(file_id)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn exists(&self, file_id: &str) -> std::io::Result<bool> {
+        match self.client.head_object().bucket(&self.bucket).key(file_id).send().await {
+            Ok(_) => Ok(true),
+            Err(e) => match e.into_service_error() {
+                e if e.is_not_found() => Ok(false),
+                e => Err(s3_error(e)),
+            },
+        }
+    }
+}
+
+// File ids become file names, so keep them to a safe character set
+fn is_valid_file_id(file_id: &str) -> bool {
+    !file_id.is_empty()
+        && file_id != "."
+        && file_id != ".."
+        && file_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+async fn read_part(part: Part) -> Result<Vec<u8>, warp::Error> {
+    part.stream()
+        .try_fold(Vec::new(), |mut data, buf| async move {
+            data.extend_from_slice(buf.chunk());
+            Ok(data)
+        })
+        .await
+}
+
+fn json_error(message: &str, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status,
+    )
+}
+
+// Rejects API requests without the right `Authorization: Bearer <key>`
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+// Compare the SHA-256 of both sides so neither the key nor its length leaks
+// through how long the comparison takes
+fn api_key_matches(given: &str, expected: &str) -> bool {
+    Sha256::digest(given.as_bytes())
+        .iter()
+        .zip(Sha256::digest(expected.as_bytes()).iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+// Passes requests through when no key is configured, otherwise only those
+// carrying it as a bearer token
+fn require_api_key(
+    api_key: Option<Arc<str>>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |auth: Option<String>| {
+            let api_key = api_key.clone();
+            async move {
+                let expected = match api_key {
+                    Some(key) => key,
+                    None => return Ok(()),
+                };
+                match auth.as_deref().and_then(|auth| auth.strip_prefix("Bearer ")) {
+                    Some(given) if api_key_matches(given, &expected) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Answer a failed key check with a JSON 401; leave other rejections to warp
+async fn handle_rejection(rejection: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(json_error("missing or invalid API key", StatusCode::UNAUTHORIZED))
+    } else {
+        Err(rejection)
+    }
+}
+
+// Token bucket holding up to one second's worth of the bandwidth limit.
+// Tokens are bytes; a transfer may overdraw the bucket and then has to wait
+// for it to refill back to zero.
+struct TokenBucket {
+    bytes_per_sec: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(bandwidth_mbps: u32) -> Self {
+        let bytes_per_sec = bandwidth_mbps.max(1) as f64 * 1_000_000.0 / 8.0;
+        TokenBucket {
+            bytes_per_sec,
+            tokens: bytes_per_sec,
+            updated: Instant::now(),
+        }
+    }
+
+    fn set_rate(&mut self, bandwidth_mbps: u32) {
+        self.refill();
+        self.bytes_per_sec = bandwidth_mbps.max(1) as f64 * 1_000_000.0 / 8.0;
+        self.tokens = self.tokens.min(self.bytes_per_sec);
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.updated = Instant::now();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+    }
+
+    fn is_exhausted(&mut self) -> bool {
+        self.refill();
+        self.tokens <= 0.0
+    }
+
+    // Spend `bytes` and return how long to wait before the bucket is back in credit
+    fn take(&mut self, bytes: usize) -> Duration {
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+// Meters and throttles storage transfers against `available_bandwidth`, and
+// caps how many run at once
+struct Bandwidth {
+    bucket: Mutex<TokenBucket>,
+    max_transfers: usize,
+    transfer_slots: Arc<Semaphore>,
+    // Transfers waiting for a slot
+    queued_transfers: AtomicUsize,
+    // Bytes moved in the current one-second window
+    sent_window: AtomicU64,
+    received_window: AtomicU64,
+    // Bytes moved in the last complete window
+    sent_per_sec: AtomicU64,
+    received_per_sec: AtomicU64,
+}
+
+impl Bandwidth {
+    fn new(bandwidth_mbps: u32, max_transfers: usize) -> Self {
+        Bandwidth {
+            bucket: Mutex::new(TokenBucket::new(bandwidth_mbps)),
+            max_transfers,
+            transfer_slots: Arc::new(Semaphore::new(max_transfers)),
+            queued_transfers: AtomicUsize::new(0),
+            sent_window: AtomicU64::new(0),
+            received_window: AtomicU64::new(0),
+            sent_per_sec: AtomicU64::new(0),
+            received_per_sec: AtomicU64::new(0),
+        }
+    }
+
+    fn set_limit(&self, bandwidth_mbps: u32) {
+        self.bucket.lock().unwrap().set_rate(bandwidth_mbps);
+    }
+
+    // Whether a new transfer may start
+    fn admit(&self) -> bool {
+        !self.bucket.lock().unwrap().is_exhausted()
+    }
+
+    // Wait until fewer than `max_transfers` transfers are running. The
+    // transfer holds its slot until the permit is dropped.
+    async fn transfer_slot(&self) -> OwnedSemaphorePermit {
+        self.queued_transfers.fetch_add(1, Ordering::Relaxed);
+        let _queued = QueuedTransfer(&self.queued_transfers);
+        self.transfer_slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("transfer slots are never closed")
+    }
+
+    fn active_transfers(&self) -> usize {
+        self.max_transfers.saturating_sub(self.transfer_slots.available_permits())
+    }
+
+    // Account for outbound bytes, waiting as long as the limit requires
+    async fn pace_sent(&self, bytes: usize) {
+        self.sent_window.fetch_add(bytes as u64, Ordering::Relaxed);
+        let delay = self.bucket.lock().unwrap().take(bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // Account for inbound bytes, which have already arrived and can't be slowed,
+    // but still draw down the bucket so outbound transfers back off
+    fn record_received(&self, bytes: usize) {
+        self.received_window.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.bucket.lock().unwrap().take(bytes);
+    }
+
+    // Close the current one-second window
+    fn roll_window(&self) {
+        self.sent_per_sec
+            .store(self.sent_window.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+        self.received_per_sec
+            .store(self.received_window.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+// Leaves the queue when its transfer starts, or gives up waiting
+struct QueuedTransfer<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedTransfer<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Stream `reader` as a response body paced by the bandwidth limit, keeping
+// `guard` (such as a transfer slot) alive until the body is done
+fn paced_body<R, G>(reader: R, bandwidth: Arc<Bandwidth>, guard: G) -> Body
+where
+    R: AsyncRead + Send + 'static,
+    G: Send + 'static,
+{
+    Body::wrap_stream(ReaderStream::new(reader).then(move |chunk| {
+        let _guard = &guard;
+        let bandwidth = bandwidth.clone();
+        async move {
+            if let Ok(chunk) = &chunk {
+                bandwidth.pace_sent(chunk.len()).await;
+            }
+            chunk
+        }
+    }))
+}
+
+// Handle `POST /api/files`: store the `file` part under its SHA-256 and
+// replicate it to other nodes
+async fn upload_file(
+    form: FormData,
+    replicator: Replicator,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible> {
+    let node = &replicator.node;
+    if !replicator.bandwidth.admit() {
+        return Ok(json_error("bandwidth limit reached, retry later", StatusCode::SERVICE_UNAVAILABLE));
+    }
+    // Uploads past the transfer limit wait their turn before reading the form
+    let slot = replicator.bandwidth.transfer_slot().await;
+    let mut file_id = None;
+    let mut ttl_secs = None;
+    let mut data = None;
+    let parts: Vec<Part> = match form.try_collect().await {
+        Ok(parts) => parts,
+        Err(e) => return Ok(json_error(&format!("invalid multipart body: {}", e), StatusCode::BAD_REQUEST)),
+    };
+    for part in parts {
+        let name = part.name().to_string();
+        let bytes = match read_part(part).await {
+            Ok(bytes) => bytes,
+            Err(e) => return Ok(json_error(&format!("failed to read upload: {}", e), StatusCode::BAD_REQUEST)),
+        };
+        match name.as_str() {
+            "file_id" => file_id = Some(String::from_utf8_lossy(&bytes).into_owned()),
+            "ttl_secs" => match String::from_utf8_lossy(&bytes).trim().parse::<u64>() {
+                Ok(ttl) => ttl_secs = Some(ttl),
+                Err(_) => return Ok(json_error("`ttl_secs` must be a whole number of seconds", StatusCode::BAD_REQUEST)),
+            },
+            "file" => data = Some(bytes),
+            _ => {}
+        }
+    }
+
+    let data = match data {
+        Some(data) => data,
+        None => return Ok(json_error("expected a `file` part", StatusCode::BAD_REQUEST)),
+    };
+    replicator.bandwidth.record_received(data.len());
+
+    // A `file_id` field is optional, but if given it must be the content hash
+    let digest = content_id(&data);
+    if file_id.map_or(false, |claimed| claimed != digest) {
+        return Ok(json_error("`file_id` does not match the SHA-256 of the file", StatusCode::BAD_REQUEST));
+    }
+    let file_id = digest;
+
+    // Reserve before writing so concurrent uploads can't overcommit
+    let size_bytes = data.len() as u64;
+    let disk_free = disk_free_bytes(&replicator.files_dir);
+    {
+        let mut node = node.write().await;
+        if node.stored_files.contains(&file_id) {
+            // Same content, same id: nothing new to store
+            drop(node);
+            let replicas = replicator.replicas(&file_id).await;
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "file_id": file_id,
+                    "size_bytes": size_bytes,
+                    "replicas": replicas
+                })),
+                StatusCode::OK,
+            ));
+        }
+        if !disk_has_room(&node, disk_free, size_bytes) {
+            return Ok(json_error("not enough free disk space", StatusCode::INSUFFICIENT_STORAGE));
+        }
+        if !node.reserve_storage(size_bytes) {
+            return Ok(json_error("not enough storage available", StatusCode::INSUFFICIENT_STORAGE));
+        }
+        node.stored_files.push(file_id.clone());
+        node.file_sizes.insert(file_id.clone(), size_bytes);
+    }
+
+    // Goes into the store the way a received transfer does, through a file
+    let staged = replicator.files_dir.join(format!("{}.upload", file_id));
+    let stored = match tokio::fs::write(&staged, &data).await {
+        Ok(()) => replicator.store.put_file(&file_id, &staged).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = stored {
+        let _ = tokio::fs::remove_file(&staged).await;
+        error!("Failed to store {}: {}", file_id, e);
+        let mut node = node.write().await;
+        node.release_storage(size_bytes);
+        node.stored_files.retain(|f| f != &file_id);
+        node.file_sizes.remove(&file_id);
+        return Ok(json_error("failed to store file", StatusCode::INTERNAL_SERVER_ERROR));
+    }
+    info!("Stored uploaded file {} ({} bytes)", file_id, size_bytes);
+    {
+        let mut node = node.write().await;
+        node.set_file_ttl(&file_id, size_bytes, ttl_secs);
+        node.events.record(NodeEvent::FileStored {
+            file_id: file_id.clone(),
+            size_bytes,
+        });
+    }
+
+    // Pushing the copies takes a slot of its own
+    drop(slot);
+    let reader: BlobReader = Box::new(std::io::Cursor::new(data));
+    let replicas = replicator.replicate(&file_id, size_bytes, reader).await;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "file_id": file_id,
+            "size_bytes": size_bytes,
+            "replicas": replicas
+        })),
+        StatusCode::CREATED,
+    ))
+}
+
+// Files are transferred in chunks of this size. Base64 grows a chunk by a
+// third, which keeps each ChunkOffer under MIN_MESSAGE_BYTES.
+const CHUNK_SIZE: usize = 512 * 1024;
+
+// An accepted transfer is abandoned if no chunk arrives for this long
+const CHUNK_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn chunk_count(size_bytes: u64) -> u32 {
+    ((size_bytes + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64).max(1) as u32
+}
+
+// A file we reserved space for, being reassembled in `<file_id>.part`
+struct IncomingTransfer {
+    size_bytes: u64,
+    ttl_secs: Option<u64>,
+    received: HashSet<u32>,
+    last_activity: Instant,
+}
+
+fn part_path(files_dir: &Path, file_id: &str) -> PathBuf {
+    files_dir.join(format!("{}.part", file_id))
+}
+
+// Move a reassembled transfer from its `.part` file into the store
+async fn store_part(part: &Path, file_id: &str, store: &dyn BlobStore) -> std::io::Result<()> {
+    store.put_file(file_id, part).await
+}
+
+// Write a chunk at its offset, so chunks can arrive in any order
+async fn write_chunk(path: &Path, chunk_index: u32, bytes: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(path)
+        .await?;
+    file.seek(std::io::SeekFrom::Start(chunk_index as u64 * CHUNK_SIZE as u64))
+        .await?;
+    file.write_all(bytes).await
+}
+
+// How long an upload waits for StorageOffers before choosing among them
+const STORAGE_OFFER_WINDOW: Duration = Duration::from_secs(5);
+
+// Gather the node ids that accepted a StorageRequest until the window closes
+async fn collect_storage_offers(
+    mut offers: mpsc::UnboundedReceiver<String>,
+    window: Duration,
+) -> Vec<String> {
+    let deadline = tokio::time::Instant::now() + window;
+    let mut accepted = Vec::new();
+    while let Ok(Some(node_id)) = tokio::time::timeout_at(deadline, offers.recv()).await {
+        if !accepted.contains(&node_id) {
+            accepted.push(node_id);
+        }
+    }
+    accepted
+}
+
+// Rank offering nodes by free storage in the registry and take the best
+// `count`; nodes that haven't announced their resources yet rank last
+fn choose_storage_peers(node: &NodeState, offers: &[String], count: usize) -> Vec<String> {
+    let mut ranked = offers.to_vec();
+    ranked.sort_by_key(|node_id| {
+        std::cmp::Reverse(
+            node.network_resources
+                .get(node_id)
+                .map_or(0, |record| record.storage_gb),
+        )
+    });
+    ranked.truncate(count);
+    ranked
+}
+
+// Everything needed to place copies of a locally held file on other nodes
+#[derive(Clone)]
+struct Replicator {
+    node: Arc<RwLock<NodeState>>,
+    store: Arc<dyn BlobStore>,
+    // Local disk, whose free space bounds uploads
+    files_dir: PathBuf,
+    publisher: mpsc::UnboundedSender<(IdentTopic, Vec<u8>)>,
+    topic: IdentTopic,
+    replication_factor: usize,
+    bandwidth: Arc<Bandwidth>,
+}
+
+impl Replicator {
+    // Top a file up to the replication factor: ask the network for storage,
+    // push the `size_bytes` in `reader` to the best offers and return the
+    // file's remote holders
+    async fn replicate(&self, file_id: &str, size_bytes: u64, reader: BlobReader) -> Vec<String> {
+        let (node_id, needed, ttl_secs) = {
+            let node = self.node.read().await;
+            let held = node.file_replicas.get(file_id).map_or(0, |r| r.len());
+            // Copies expire along with the original
+            let ttl_secs = node.file_ttls.get(file_id).map(|ttl| ttl.remaining_secs(unix_secs()));
+            (node.node_id.clone(), self.replication_factor.saturating_sub(held), ttl_secs)
+        };
+        if needed == 0 {
+            return self.replicas(file_id).await;
+        }
+
+        let (offer_sender, offer_rcv) = mpsc::unbounded_channel();
+        self.node
+            .write()
+            .await
+            .storage_offers
+            .insert(file_id.to_string(), offer_sender);
+        let request = OpenSkyCommand::StorageRequest {
+            file_id: file_id.to_string(),
+            size_bytes,
+            node_id: node_id.clone(),
+            ttl_secs,
+        };
+        self.publish(&request);
+
+        let offers = collect_storage_offers(offer_rcv, STORAGE_OFFER_WINDOW).await;
+        let targets = {
+            let mut node = self.node.write().await;
+            node.storage_offers.remove(file_id);
+            let holders = node.file_replicas.get(file_id).cloned().unwrap_or_default();
+            let offers: Vec<String> = offers.into_iter().filter(|o| !holders.contains(o)).collect();
+            choose_storage_peers(&node, &offers, needed)
+        };
+
+        if targets.is_empty() {
+            info!("No peer offered to store a copy of {}", file_id);
+        }
+        push_chunks(&self.publisher, &self.topic, &self.bandwidth, &node_id, &targets, file_id, size_bytes, reader).await;
+
+        {
+            let mut node = self.node.write().await;
+            node.file_replicas
+                .entry(file_id.to_string())
+                .or_default()
+                .extend(targets);
+            node.dirty = true;
+        }
+        self.replicas(file_id).await
+    }
+
+    // Re-read a local file and bring it back up to the replication factor
+    async fn rereplicate(&self, file_id: &str) {
+        match self.store.get_reader(file_id).await {
+            Ok(Some((size_bytes, reader))) => {
+                let replicas = self.replicate(file_id, size_bytes, reader).await;
+                info!("File {} now has {} remote replicas", file_id, replicas.len());
+            }
+            Ok(None) => error!("Failed to read {} for re-replication: not in the store", file_id),
+            Err(e) => error!("Failed to read {} for re-replication: {}", file_id, e),
+        }
+    }
+
+    async fn replicas(&self, file_id: &str) -> Vec<String> {
+        self.node
+            .read()
+            .await
+            .file_replicas
+            .get(file_id)
+            .map(|r| r.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn publish(&self, command: &OpenSkyCommand) {
+        let json = serde_json::to_vec(command).expect("Failed to serialize");
+        let _ = self.publisher.send((self.topic.clone(), json));
+    }
+}
+
+// Send the `size_bytes` read from `reader` to each of `target_ids` as
+// ChunkOffers on `topic`, paced to the bandwidth limit. Only one chunk is
+// held at a time, and it goes to every target before the next is read.
+#[allow(clippy::too_many_arguments)]
+async fn push_chunks(
+    publisher: &mpsc::UnboundedSender<(IdentTopic, Vec<u8>)>,
+    topic: &IdentTopic,
+    bandwidth: &Bandwidth,
+    node_id: &str,
+    target_ids: &[String],
+    file_id: &str,
+    size_bytes: u64,
+    mut reader: impl AsyncRead + Unpin,
+) {
+    if target_ids.is_empty() {
+        return;
+    }
+    // An empty file still takes one (empty) chunk
+    let total_chunks = chunk_count(size_bytes);
+    let _slot = bandwidth.transfer_slot().await;
+    info!("Sending file {} to {} in {} chunks", file_id, target_ids.join(", "), total_chunks);
+    for chunk_index in 0..total_chunks {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        if let Err(e) = AsyncReadExt::take(&mut reader, CHUNK_SIZE as u64).read_to_end(&mut chunk).await {
+            error!("Failed to read {} while sending it: {}", file_id, e);
+            return;
+        }
+        let encoded = base64::encode(&chunk);
+        for target_id in target_ids {
+            bandwidth.pace_sent(encoded.len()).await;
+            let command = OpenSkyCommand::ChunkOffer {
+                file_id: file_id.to_string(),
+                node_id: node_id.to_string(),
+                target_id: target_id.clone(),
+                chunk_index,
+                total_chunks,
+                data: encoded.clone(),
+            };
+            let json = serde_json::to_vec(&command).expect("Failed to serialize");
+            let _ = publisher.send((topic.clone(), json));
+        }
+    }
+}
+
+// How long a fetch waits for some peer to say it holds the file
+const HOLDER_WINDOW: Duration = Duration::from_secs(10);
+
+// Why a file couldn't be fetched from the network
+enum FetchError {
+    // No peer answered that it holds the file
+    NoHolder,
+    // A holder answered but the transfer didn't complete
+    Failed(String),
+}
+
+// Make sure a file is in our store, fetching a copy from a peer that holds
+// it if need be. A fetch that stalls is abandoned like any transfer.
+async fn fetch_file(handle: &NodeHandle, file_id: &str) -> Result<(), FetchError> {
+    let (waiter, mut arrived) = oneshot::channel();
+    {
+        let mut node = handle.node.write().await;
+        let incoming = node.incoming_transfers.contains_key(file_id);
+        if node.stored_files.iter().any(|f| f == file_id) && !incoming {
+            return Ok(());
+        }
+        let waiters = node.input_fetches.entry(file_id.to_string()).or_default();
+        // Waiters that gave up leave closed senders behind
+        waiters.retain(|waiter| !waiter.is_closed());
+        let first = waiters.is_empty();
+        waiters.push(waiter);
+        if first && !incoming {
+            info!("Fetching file {} from the network", file_id);
+            handle.publish(&OpenSkyCommand::FileFetch {
+                file_id: file_id.to_string(),
+                node_id: handle.peer_id.to_string(),
+            });
+        }
+    }
+    let result = match tokio::time::timeout(HOLDER_WINDOW, &mut arrived).await {
+        Ok(result) => result,
+        // Someone answered and the file is on its way
+        Err(_) if handle.node.read().await.incoming_transfers.contains_key(file_id) => arrived.await,
+        Err(_) => return Err(FetchError::NoHolder),
+    };
+    match result {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(reason)) => Err(FetchError::Failed(reason)),
+        Err(_) => Err(FetchError::Failed("the transfer was abandoned".into())),
+    }
+}
+
+// Fetch a task's inputs and copy them into `dir`, each named by its file_id
+async fn stage_inputs(handle: &NodeHandle, store: &dyn BlobStore, dir: &Path, inputs: &[String]) -> Result<(), String> {
+    futures::future::try_join_all(inputs.iter().map(|file_id| async move {
+        fetch_file(handle, file_id).await.map_err(|e| match e {
+            FetchError::NoHolder => format!("no peer holds input {}", file_id),
+            FetchError::Failed(reason) => format!("failed to fetch input {}: {}", file_id, reason),
+        })
+    }))
+    .await?;
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    for file_id in inputs {
+        let mut reader = match store.get_reader(file_id).await {
+            Ok(Some((_, reader))) => reader,
+            Ok(None) => return Err(format!("input {} is no longer stored here", file_id)),
+            Err(e) => return Err(format!("failed to read input {}: {}", file_id, e)),
+        };
+        let staged = async {
+            let mut file = tokio::fs::File::create(dir.join(file_id)).await?;
+            tokio::io::copy(&mut reader, &mut file).await
+        };
+        staged
+            .await
+            .map_err(|e| format!("failed to stage input {}: {}", file_id, e))?;
+    }
+    Ok(())
+}
+
+// Keep a successful task's output files as stored files. Returns the file_id
+// of each requested path captured, and a warning for each that wasn't.
+async fn store_outputs(
+    node: &Arc<RwLock<NodeState>>,
+    store: &dyn BlobStore,
+    files_dir: &Path,
+    requested: &[String],
+    mut files: HashMap<String, Vec<u8>>,
+) -> (HashMap<String, String>, Vec<String>) {
+    let mut stored = HashMap::new();
+    let mut warnings = Vec::new();
+    for path in requested {
+        let data = match files.remove(path) {
+            Some(data) => data,
+            None => {
+                warnings.push(format!("output {} was not produced", path));
+                continue;
+            }
+        };
+        let file_id = content_id(&data);
+        let size_bytes = data.len() as u64;
+        let disk_free = disk_free_bytes(files_dir);
+        {
+            let mut node = node.write().await;
+            if node.stored_files.contains(&file_id) {
+                stored.insert(path.clone(), file_id);
+                continue;
+            }
+            if !disk_has_room(&node, disk_free, size_bytes) || !node.reserve_storage(size_bytes) {
+                warnings.push(format!("no storage left for output {}", path));
+                continue;
+            }
+            node.stored_files.push(file_id.clone());
+            node.file_sizes.insert(file_id.clone(), size_bytes);
+            node.dirty = true;
+        }
+        let mut node = match store.put(&file_id, data).await {
+            Ok(()) => node.write().await,
+            Err(e) => {
+                let mut node = node.write().await;
+                node.release_storage(size_bytes);
+                node.stored_files.retain(|f| f != &file_id);
+                node.file_sizes.remove(&file_id);
+                warnings.push(format!("failed to store output {}: {}", path, e));
+                continue;
+            }
+        };
+        node.events.record(NodeEvent::FileStored { file_id: file_id.clone(), size_bytes });
+        stored.insert(path.clone(), file_id);
+    }
+    (stored, warnings)
+}
+
+// Parse a single `bytes=` range into inclusive `(start, end)` offsets,
+// returning `None` if it's malformed or can't be satisfied
+fn parse_range(range: &str, total: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // Suffix range: the last N bytes
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (total.checked_sub(suffix.min(total))?, total.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, total.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(total.checked_sub(1)?)),
+    };
+    if start > end || start >= total {
+        return None;
+    }
+    Some((start, end))
+}
+
+// Handle `GET /api/files/<file_id>`, honouring a `Range` header so large
+// downloads can be resumed. With `?remote=true` a file we don't hold is
+// fetched from a peer that does first, and kept unless `cache=false`.
+async fn download_file(
+    file_id: String,
+    range: Option<String>,
+    query: DownloadQuery,
+    handle: NodeHandle,
+    store: Arc<dyn BlobStore>,
+    bandwidth: Arc<Bandwidth>,
+) -> Result<Response<Body>, Infallible> {
+    let node = &handle.node;
+    let not_found = || {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    if !is_valid_file_id(&file_id) {
+        return Ok(not_found());
+    }
+    let stored = node.read().await.stored_files.contains(&file_id);
+    if !stored && !query.remote {
+        return Ok(not_found());
+    }
+    if !bandwidth.admit() {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::RETRY_AFTER, "1")
+            .body(Body::empty())
+            .unwrap());
+    }
+    // Downloads past the transfer limit wait their turn before reading anything
+    let slot = bandwidth.transfer_slot().await;
+    if !stored {
+        match fetch_file(&handle, &file_id).await {
+            Ok(()) => {}
+            Err(FetchError::NoHolder) => return Ok(not_found()),
+            Err(FetchError::Failed(reason)) => {
+                warn!("Failed to fetch {} for download: {}", file_id, reason);
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        }
+    }
+
+    let internal_error = || {
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap()
+    };
+    // Refuse to serve content that no longer matches its id. Hashing takes a
+    // pass of its own, so corruption is caught before any of it is sent.
+    let digest = match store.get_reader(&file_id).await {
+        Ok(Some((_, reader))) => reader_digest(reader).await,
+        Ok(None) => return Ok(not_found()),
+        Err(e) => Err(e),
+    };
+    match digest {
+        Ok(digest) if digest == file_id => {}
+        Ok(digest) => {
+            error!("Stored file {} is corrupted (content hash {})", file_id, digest);
+            return Ok(internal_error());
+        }
+        Err(e) => {
+            error!("Failed to read stored file {}: {}", file_id, e);
+            return Ok(internal_error());
+        }
+    }
+    let (total, mut file) = match store.get_reader(&file_id).await {
+        Ok(Some(found)) => found,
+        // The file may be reserved but its data not yet received
+        Ok(None) => return Ok(not_found()),
+        Err(e) => {
+            error!("Failed to read stored file {}: {}", file_id, e);
+            return Ok(internal_error());
+        }
+    };
+    // A copy fetched only to pass it on goes once the body has been sent
+    let eviction = (!stored && !query.cache.unwrap_or(true)).then(|| Eviction {
+        node: node.clone(),
+        store: store.clone(),
+        file_id: file_id.clone(),
+    });
+
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    let response = match range {
+        Some(range) => {
+            let (start, end) = match parse_range(&range, total) {
+                Some(range) => range,
+                None => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+                        .body(Body::empty())
+                        .unwrap())
+                }
+            };
+            // Readers only go forward, so skip to the start of the range
+            if let Err(e) = tokio::io::copy(&mut AsyncReadExt::take(&mut file, start), &mut tokio::io::sink()).await {
+                error!("Failed to read stored file {}: {}", file_id, e);
+                return Ok(internal_error());
+            }
+            let length = end - start + 1;
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, total))
+                .header(header::CONTENT_LENGTH, length)
+                .body(paced_body(AsyncReadExt::take(file, length), bandwidth, (slot, eviction)))
+        }
+        None => response
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, total)
+            .body(paced_body(file, bandwidth, (slot, eviction))),
+    };
+
+    Ok(response.unwrap())
+}
+
+// Forgets and deletes a file fetched for a single download when dropped
+struct Eviction {
+    node: Arc<RwLock<NodeState>>,
+    store: Arc<dyn BlobStore>,
+    file_id: String,
+}
+
+impl Drop for Eviction {
+    fn drop(&mut self) {
+        let (node, store, file_id) = (self.node.clone(), self.store.clone(), std::mem::take(&mut self.file_id));
+        tokio::spawn(async move {
+            node.write().await.forget_file(&file_id);
+            if let Err(e) = store.delete(&file_id).await {
+                error!("Failed to delete {}: {}", file_id, e);
+            }
+        });
+    }
+}
+
+// Create the data directory if it doesn't exist, and check up front that we
+// can write there rather than failing on the first save
+fn prepare_data_dir(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(data_dir)
+        .map_err(|e| format!("failed to create data directory {}: {} (set OPENSKY_DATA_DIR)", data_dir.display(), e))?;
+    let probe = data_dir.join(".write-test");
+    fs::write(&probe, b"")
+        .and_then(|()| fs::remove_file(&probe))
+        .map_err(|e| format!("data directory {} is not writable: {} (set OPENSKY_DATA_DIR)", data_dir.display(), e))?;
+    Ok(())
+}
+
+// Read a protobuf-encoded keypair, generating and saving a new ed25519 one
+// with owner-only permissions if the file doesn't exist yet
+fn load_or_create_identity(path: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(identity::Keypair::from_protobuf_encoding(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let keypair = identity::Keypair::generate_ed25519();
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?;
+            file.write_all(&keypair.to_protobuf_encoding()?)?;
+            info!("Generated new identity at {}", path.display());
+            Ok(keypair)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Node configuration. Built-in defaults are overridden by the TOML file given
+/// with `--config <path>` or `OPENSKY_CONFIG`, which is in turn overridden by
+/// the `OPENSKY_*` environment variables.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Holds saved state, the identity and stored files
+    pub data_dir: PathBuf,
+    pub resources: ResourcesConfig,
+    pub networking: NetworkingConfig,
+    pub gossip: GossipConfig,
+    pub limits: LimitsConfig,
+    pub security: SecurityConfig,
+    pub reputation: ReputationConfig,
+    pub storage: StorageConfig,
+    /// What runs accepted tasks
+    pub executor: ExecutorKind,
+}
+
+/// How a node runs the tasks it accepts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutorKind {
+    /// In containers, with [`DockerExecutor`]
+    Docker,
+    /// Not at all, with [`MockExecutor`]: a dry run for demos and tests
+    Mock,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            data_dir: PathBuf::from("/data"),
+            resources: ResourcesConfig::default(),
+            networking: NetworkingConfig::default(),
+            gossip: GossipConfig::default(),
+            limits: LimitsConfig::default(),
+            security: SecurityConfig::default(),
+            reputation: ReputationConfig::default(),
+            storage: StorageConfig::default(),
+            executor: ExecutorKind::default(),
+        }
+    }
+}
+
+impl Default for ExecutorKind {
+    fn default() -> Self {
+        ExecutorKind::Docker
+    }
+}
+
+/// Where a node keeps the files it stores
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// S3 settings, used only by the `s3` backend
+    pub s3_bucket: Option<String>,
+    /// For S3-compatible stores other than AWS, such as `http://minio:9000`
+    pub s3_endpoint: Option<String>,
+    pub s3_region: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+}
+
+/// Which [`BlobStore`] holds stored files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// On local disk under the data directory, with [`LocalFsStore`]
+    Fs,
+    /// In an S3-compatible bucket, with [`S3Store`]
+    S3,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        StorageBackend::Fs
+    }
+}
+
+/// What this node offers to the network
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourcesConfig {
+    pub cpu_percent: u8,
+    pub storage_gb: u32,
+    pub bandwidth_mbps: u32,
+    /// Most storage any one peer may use here; unlimited if unset
+    pub max_storage_per_peer_gb: Option<u32>,
+}
+
+impl Default for ResourcesConfig {
+    fn default() -> Self {
+        ResourcesConfig {
+            cpu_percent: 50,
+            storage_gb: 10,
+            bandwidth_mbps: 50,
+            max_storage_per_peer_gb: None,
+        }
+    }
+}
+
+/// Where this node listens and who it talks to
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkingConfig {
+    pub listen: Vec<String>,
+    pub bootstrap: Vec<String>,
+    /// Relays to reserve a slot on, as `/.../p2p/<relay peer id>`
+    pub relays: Vec<String>,
+    /// Gossipsub topics to join; offers go to all of them, and tasks to the
+    /// first unless submitted to another
+    #[serde(alias = "topic", deserialize_with = "one_or_many")]
+    pub topics: Vec<String>,
+    pub api_addr: SocketAddr,
+    pub ping_interval_secs: u64,
+    pub announce_interval_secs: u64,
+    /// Peers we're not connected to and haven't heard from for this long are forgotten
+    pub peer_timeout_secs: u64,
+    /// Discover peers on the local network by multicast DNS
+    pub mdns: bool,
+}
+
+impl Default for NetworkingConfig {
+    fn default() -> Self {
+        NetworkingConfig {
+            listen: vec!["/ip4/0.0.0.0/tcp/30333".into()],
+            bootstrap: Vec::new(),
+            relays: Vec::new(),
+            topics: vec!["opensky-network".into()],
+            api_addr: ([0, 0, 0, 0], 8080).into(),
+            ping_interval_secs: 15,
+            announce_interval_secs: 60,
+            peer_timeout_secs: 300,
+            mdns: true,
+        }
+    }
+}
+
+/// Gossipsub tuning: a shorter heartbeat and a larger mesh spread messages
+/// faster at the cost of bandwidth
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipConfig {
+    pub heartbeat_ms: u64,
+    /// Peers each node keeps in its mesh per topic, and the bounds outside
+    /// which the heartbeat grafts or prunes to get back to `mesh_n`
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    /// Heartbeats of messages kept for answering IWANTs, and how many of
+    /// those are advertised in IHAVE gossip
+    pub history_length: usize,
+    pub history_gossip: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        // The gossipsub defaults
+        GossipConfig {
+            heartbeat_ms: 1000,
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            history_length: 5,
+            history_gossip: 3,
+        }
+    }
+}
+
+impl GossipConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.heartbeat_ms == 0 {
+            return Err("OPENSKY_GOSSIP_HEARTBEAT_MS must be greater than 0".into());
+        }
+        if !(1 <= self.mesh_n_low && self.mesh_n_low <= self.mesh_n && self.mesh_n <= self.mesh_n_high) {
+            return Err(format!(
+                "gossip mesh sizes must satisfy 1 <= mesh_n_low <= mesh_n <= mesh_n_high, got {} <= {} <= {}",
+                self.mesh_n_low, self.mesh_n, self.mesh_n_high
+            ));
+        }
+        if self.history_gossip == 0 || self.history_gossip > self.history_length {
+            return Err(format!(
+                "gossip history_gossip must be between 1 and history_length ({}), got {}",
+                self.history_length, self.history_gossip
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Accepts `topic = "a"` from older config files as well as `topics = ["a", "b"]`
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(topic) => vec![topic],
+        OneOrMany::Many(topics) => topics,
+    })
+}
+
+/// Bounds on the work this node accepts
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_concurrent_tasks: usize,
+    /// Outgoing file transfers and downloads beyond this wait in a queue;
+    /// incoming ones beyond it are declined
+    pub max_concurrent_transfers: usize,
+    pub task_timeout_secs: u64,
+    /// Times a failed or rejected task is resubmitted to another worker
+    pub task_max_retries: u32,
+    /// Cap on each of a task's stdout and stderr returned in its TaskResult
+    pub max_output_bytes: usize,
+    /// How long `POST /api/tasks` waits for a result before giving up
+    pub submit_timeout_secs: u64,
+    pub replication_factor: usize,
+    /// Larger messages from peers are dropped unread
+    pub max_message_bytes: usize,
+    /// A task_id seen again this soon after it finished gets the earlier
+    /// result instead of running twice
+    pub task_dedup_window_secs: u64,
+    /// Fail a task whose requested outputs can't all be captured, rather
+    /// than only warning about the missing ones
+    pub strict_outputs: bool,
+    /// Run tasks submitted here on this node when they fit, and only look
+    /// for another worker when they don't
+    pub prefer_local: bool,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_concurrent_tasks: 4,
+            max_concurrent_transfers: 4,
+            task_timeout_secs: 300,
+            task_max_retries: 3,
+            max_output_bytes: 64 * 1024,
+            submit_timeout_secs: 120,
+            replication_factor: 3,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            task_dedup_window_secs: 600,
+            strict_outputs: false,
+            prefer_local: false,
+        }
+    }
+}
+
+/// When peers get banned for misbehaving
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReputationConfig {
+    /// Peers scoring below this are banned for `ban_secs`
+    pub min_score: f64,
+    pub ban_secs: u64,
+    pub max_messages_per_min: u32,
+    /// Sustained rate of inbound commands allowed per peer, and how many
+    /// may arrive at once; the rest are dropped
+    pub messages_per_sec: f64,
+    pub message_burst: u32,
+    /// Dropped messages after which a peer is banned
+    pub max_rate_violations: u32,
+    /// Peer IDs exempt from the message rate limits
+    pub trusted_peers: Vec<String>,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        ReputationConfig {
+            min_score: 0.3,
+            ban_secs: 300,
+            max_messages_per_min: 600,
+            messages_per_sec: 20.0,
+            message_burst: 50,
+            max_rate_violations: 100,
+            trusted_peers: Vec::new(),
+        }
+    }
+}
+
+/// Identity and what peers are allowed to run here
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// `identity.key` in the data directory if unset
+    pub identity_path: Option<PathBuf>,
+    /// Docker images tasks may run; empty denies every task
+    pub image_allowlist: Vec<String>,
+    pub replay_window_secs: u64,
+    pub nonce_cache_size: NonZeroUsize,
+    /// Bearer token the HTTP API requires; the API is open if unset
+    pub api_key: Option<String>,
+    /// PEM certificate and key to serve the API over HTTPS; both or neither
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Peer ids of the only nodes we talk to; anyone if empty
+    pub allowed_peers: Vec<String>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        SecurityConfig {
+            identity_path: None,
+            image_allowlist: Vec::new(),
+            replay_window_secs: 60,
+            nonce_cache_size: NonZeroUsize::new(10000).unwrap(),
+            api_key: None,
+            tls_cert: None,
+            tls_key: None,
+            allowed_peers: Vec::new(),
+        }
+    }
+}
+
+impl SecurityConfig {
+    // The certificate and key to serve the API with, if TLS is configured,
+    // checking up front that both are given and readable
+    fn tls_files(&self) -> Result<Option<(&Path, &Path)>, Box<dyn Error>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                for path in [cert, key] {
+                    fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                }
+                Ok(Some((cert, key)))
+            }
+            (None, None) => Ok(None),
+            _ => Err("OPENSKY_TLS_CERT and OPENSKY_TLS_KEY must be set together".into()),
+        }
+    }
+}
+
+// Replace `value` with the parsed contents of the environment variable, if set
+fn env_override<T>(value: &mut T, name: &str) -> Result<(), Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Error + 'static,
+{
+    if let Ok(raw) = env::var(name) {
+        *value = raw
+            .parse()
+            .map_err(|e| format!("invalid {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+// Like `env_override`, for comma-separated lists
+fn env_override_list(value: &mut Vec<String>, name: &str) {
+    if let Ok(raw) = env::var(name) {
+        *value = raw
+            .split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
+
+// The path passed as `--config <path>` or `--config=<path>`, else `OPENSKY_CONFIG`
+fn config_path() -> Option<PathBuf> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    env::var("OPENSKY_CONFIG").ok().map(PathBuf::from)
+}
+
+impl NodeConfig {
+    /// The configuration for this process: the config file named on the
+    /// command line or in `OPENSKY_CONFIG`, with environment overrides
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let mut config = match config_path() {
+            Some(path) => {
+                let raw = fs::read_to_string(&path)
+                    .map_err(|e| format!("failed to read config {}: {}", path.display(), e))?;
+                toml::from_str(&raw)
+                    .map_err(|e| format!("invalid config {}: {}", path.display(), e))?
+            }
+            None => NodeConfig::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), Box<dyn Error>> {
+        env_override(&mut self.data_dir, "OPENSKY_DATA_DIR")?;
+        env_override(&mut self.resources.cpu_percent, "OPENSKY_MAX_CPU_PERCENT")?;
+        env_override(&mut self.resources.storage_gb, "OPENSKY_MAX_STORAGE_GB")?;
+        env_override(&mut self.resources.bandwidth_mbps, "OPENSKY_MAX_BANDWIDTH_MBPS")?;
+        if let Ok(raw) = env::var("OPENSKY_MAX_STORAGE_PER_PEER_GB") {
+            let gb = raw
+                .parse()
+                .map_err(|e| format!("invalid OPENSKY_MAX_STORAGE_PER_PEER_GB: {}", e))?;
+            self.resources.max_storage_per_peer_gb = Some(gb);
+        }
+        env_override_list(&mut self.networking.listen, "OPENSKY_P2P_LISTEN");
+        env_override_list(&mut self.networking.bootstrap, "OPENSKY_BOOTSTRAP");
+        env_override_list(&mut self.networking.relays, "OPENSKY_RELAY");
+        env_override_list(&mut self.networking.topics, "OPENSKY_TOPIC");
+        env_override(&mut self.networking.api_addr, "OPENSKY_API_ADDR")?;
+        env_override(&mut self.networking.ping_interval_secs, "OPENSKY_PING_INTERVAL_SECS")?;
+        env_override(&mut self.networking.announce_interval_secs, "OPENSKY_ANNOUNCE_INTERVAL_SECS")?;
+        env_override(&mut self.networking.peer_timeout_secs, "OPENSKY_PEER_TIMEOUT_SECS")?;
+        env_override(&mut self.networking.mdns, "OPENSKY_MDNS")?;
+        env_override(&mut self.gossip.heartbeat_ms, "OPENSKY_GOSSIP_HEARTBEAT_MS")?;
+        env_override(&mut self.gossip.mesh_n, "OPENSKY_GOSSIP_MESH_N")?;
+        env_override(&mut self.gossip.mesh_n_low, "OPENSKY_GOSSIP_MESH_N_LOW")?;
+        env_override(&mut self.gossip.mesh_n_high, "OPENSKY_GOSSIP_MESH_N_HIGH")?;
+        env_override(&mut self.gossip.history_length, "OPENSKY_GOSSIP_HISTORY_LENGTH")?;
+        env_override(&mut self.gossip.history_gossip, "OPENSKY_GOSSIP_HISTORY_GOSSIP")?;
+        env_override(&mut self.limits.max_concurrent_tasks, "OPENSKY_MAX_CONCURRENT_TASKS")?;
+        env_override(&mut self.limits.max_concurrent_transfers, "OPENSKY_MAX_CONCURRENT_TRANSFERS")?;
+        env_override(&mut self.limits.task_timeout_secs, "OPENSKY_TASK_TIMEOUT_SECS")?;
+        env_override(&mut self.limits.task_max_retries, "OPENSKY_TASK_MAX_RETRIES")?;
+        env_override(&mut self.limits.max_output_bytes, "OPENSKY_MAX_OUTPUT_BYTES")?;
+        env_override(&mut self.limits.submit_timeout_secs, "OPENSKY_SUBMIT_TIMEOUT_SECS")?;
+        env_override(&mut self.limits.replication_factor, "OPENSKY_REPLICATION_FACTOR")?;
+        env_override(&mut self.limits.max_message_bytes, "OPENSKY_MAX_MESSAGE_BYTES")?;
+        env_override(&mut self.limits.task_dedup_window_secs, "OPENSKY_TASK_DEDUP_WINDOW_SECS")?;
+        env_override(&mut self.limits.strict_outputs, "OPENSKY_STRICT_OUTPUTS")?;
+        env_override(&mut self.limits.prefer_local, "OPENSKY_PREFER_LOCAL")?;
+        if let Ok(path) = env::var("OPENSKY_IDENTITY_PATH") {
+            self.security.identity_path = Some(PathBuf::from(path));
+        }
+        env_override_list(&mut self.security.image_allowlist, "OPENSKY_IMAGE_ALLOWLIST");
+        env_override_list(&mut self.security.allowed_peers, "OPENSKY_ALLOWED_PEERS");
+        env_override(&mut self.security.replay_window_secs, "OPENSKY_REPLAY_WINDOW_SECS")?;
+        env_override(&mut self.security.nonce_cache_size, "OPENSKY_NONCE_CACHE_SIZE")?;
+        if let Ok(key) = env::var("OPENSKY_API_KEY") {
+            self.security.api_key = Some(key);
+        }
+        if let Ok(path) = env::var("OPENSKY_TLS_CERT") {
+            self.security.tls_cert = Some(PathBuf::from(path));
+        }
+        if let Ok(path) = env::var("OPENSKY_TLS_KEY") {
+            self.security.tls_key = Some(PathBuf::from(path));
+        }
+        env_override(&mut self.reputation.min_score, "OPENSKY_REPUTATION_MIN_SCORE")?;
+        env_override(&mut self.reputation.ban_secs, "OPENSKY_REPUTATION_BAN_SECS")?;
+        env_override(&mut self.reputation.max_messages_per_min, "OPENSKY_MAX_MESSAGES_PER_MIN")?;
+        env_override(&mut self.reputation.messages_per_sec, "OPENSKY_MESSAGES_PER_SEC")?;
+        env_override(&mut self.reputation.message_burst, "OPENSKY_MESSAGE_BURST")?;
+        env_override(&mut self.reputation.max_rate_violations, "OPENSKY_MAX_RATE_VIOLATIONS")?;
+        env_override_list(&mut self.reputation.trusted_peers, "OPENSKY_TRUSTED_PEERS");
+        if let Ok(backend) = env::var("OPENSKY_STORAGE_BACKEND") {
+            self.storage.backend = match backend.as_str() {
+                "fs" => StorageBackend::Fs,
+                "s3" => StorageBackend::S3,
+                _ => return Err(format!("invalid OPENSKY_STORAGE_BACKEND: {} (expected fs or s3)", backend).into()),
+            };
+        }
+        for (value, name) in [
+            (&mut self.storage.s3_bucket, "OPENSKY_S3_BUCKET"),
+            (&mut self.storage.s3_endpoint, "OPENSKY_S3_ENDPOINT"),
+            (&mut self.storage.s3_region, "OPENSKY_S3_REGION"),
+            (&mut self.storage.s3_access_key_id, "OPENSKY_S3_ACCESS_KEY_ID"),
+            (&mut self.storage.s3_secret_access_key, "OPENSKY_S3_SECRET_ACCESS_KEY"),
+        ] {
+            if let Ok(raw) = env::var(name) {
+                *value = Some(raw);
+            }
+        }
+        if let Ok(kind) = env::var("OPENSKY_EXECUTOR") {
+            self.executor = match kind.as_str() {
+                "docker" => ExecutorKind::Docker,
+                "mock" => ExecutorKind::Mock,
+                _ => return Err(format!("invalid OPENSKY_EXECUTOR: {} (expected docker or mock)", kind).into()),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Configures an [`OpenSkyNode`]
+pub struct OpenSkyNodeBuilder {
+    config: NodeConfig,
+    data_dir: Option<PathBuf>,
+    console: bool,
+    handlers: Vec<Arc<dyn CommandHandler>>,
+    executor: Option<Arc<dyn TaskExecutor>>,
+    memory_transport: bool,
+}
+
+impl OpenSkyNodeBuilder {
+    /// Use `config` instead of the built-in defaults
+    pub fn config(mut self, config: NodeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keep stored files and saved state under `dir` rather than the
+    /// configured [`NodeConfig::data_dir`]
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Read operator commands such as `peers` and `status` from stdin
+    pub fn console(mut self, enabled: bool) -> Self {
+        self.console = enabled;
+        self
+    }
+
+    /// Also pass incoming commands to `handler`, after the built-in handlers
+    pub fn handler(mut self, handler: impl CommandHandler + 'static) -> Self {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Run accepted tasks with `executor` instead of Docker
+    pub fn executor(mut self, executor: impl TaskExecutor + 'static) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Connect over an in-process transport instead of TCP, so several nodes
+    /// can share one process in tests and simulations. Listen and bootstrap
+    /// addresses are then `/memory/<port>`.
+    pub fn memory_transport(mut self, enabled: bool) -> Self {
+        self.memory_transport = enabled;
+        self
+    }
+
+    /// Load the identity, restore saved state and set up the swarm. Nothing
+    /// is listening until the node is run.
+    pub async fn build(self) -> Result<OpenSkyNode, Box<dyn Error>> {
+        let OpenSkyNodeBuilder { config, data_dir, console, handlers, executor, memory_transport } = self;
+        let max_task_timeout = Duration::from_secs(config.limits.task_timeout_secs);
+        let replay_window = Duration::from_secs(config.security.replay_window_secs);
+        let nonce_cache_size = config.security.nonce_cache_size;
+        let max_message_bytes = config.limits.max_message_bytes;
+        if max_message_bytes < MIN_MESSAGE_BYTES {
+            return Err(format!(
+                "OPENSKY_MAX_MESSAGE_BYTES must be at least {} to fit a file chunk",
+                MIN_MESSAGE_BYTES
+            )
+            .into());
+        }
+        if config.limits.max_concurrent_transfers == 0 {
+            return Err("OPENSKY_MAX_CONCURRENT_TRANSFERS must be greater than 0".into());
+        }
+        config.gossip.validate()?;
+        config.security.tls_files()?;
+
+        let data_dir = data_dir.unwrap_or_else(|| config.data_dir.clone());
+        prepare_data_dir(&data_dir)?;
+
+        // Load our identity so the PeerId stays stable across restarts
+        let identity_path = config.security.identity_path.clone().unwrap_or_else(|| data_dir.join("identity.key"));
+        let id_keys = load_or_create_identity(&identity_path)?;
+        let peer_id = PeerId::from(id_keys.public());
+        info!(peer_id = %peer_id, "Local peer id: {}", peer_id);
+
+        // Set up the transport and swarm
+        let (response_sender, response_rcv) = mpsc::unbounded_channel();
+        let (replication_sender, replication_rcv) = mpsc::unbounded_channel::<String>();
+        let (external_addr_sender, external_addr_rcv) = mpsc::unbounded_channel::<Multiaddr>();
+        let (node_update_sender, node_update_rcv) = mpsc::unbounded_channel::<NodeUpdate>();
+
+        // Create a transport with the Noise protocol for encryption, able to
+        // dial and listen through relays
+        let (relay_transport, relay_client) = RelayClient::new_transport_and_behaviour(peer_id);
+        let transport = build_transport(&id_keys, relay_transport, memory_transport).await?;
+
+        // Create the Gossipsub topics; the first is where we publish by default
+        let topics: Vec<IdentTopic> = config.networking.topics.iter().map(|t| IdentTopic::new(t.as_str())).collect();
+        let topic = topics.first().cloned().ok_or("at least one topic must be configured")?;
+
+        // Initialize node state
+        // mem_info reports kilobytes; offer half of system RAM
+        let memory_capacity = system_info::mem_info().total / 1024 / 2;
+        let node = Arc::new(RwLock::new(NodeState::new(peer_id.to_string(), &config, memory_capacity)));
+
+        // Uploaded and replicated file contents live here, or are staged here
+        // on their way to S3
+        let files_dir = data_dir.join("files");
+        fs::create_dir_all(&files_dir)?;
+        let store: Arc<dyn BlobStore> = match config.storage.backend {
+            StorageBackend::Fs => Arc::new(LocalFsStore::new(&files_dir)),
+            StorageBackend::S3 => {
+                let store = S3Store::connect(&config.storage).await?;
+                info!("Storing files in S3 bucket {}", store.bucket);
+                Arc::new(store)
+            }
+        };
+
+        // Peers we knew last time, plus the configured bootstrap peers
+        let peer_store_path = data_dir.join("peers.json");
+        let mut peer_store = PeerStore::load(&peer_store_path);
+        for (peer, addr) in config.networking.bootstrap.iter().filter_map(|addr| parse_bootstrap_addr(addr)) {
+            peer_store.learn(&peer.to_string(), [&addr]);
+        }
+        let known_peers = peer_store.dial_candidates(PEER_STORE_DIAL_COUNT);
+        node.write().await.peer_store = peer_store;
+
+        // Restore state from a previous run
+        let state_path = data_dir.join("state.json");
+        if let Some(state) = load_state(&state_path)? {
+            info!("Restoring node state from {}", state_path.display());
+            node.write().await.restore(state);
+        }
+
+        // Create a Swarm to manage peers and events
+        // The swarm is owned by the main loop, so background tasks queue outbound
+        // messages on this channel and the main loop publishes them
+        let (publish_sender, publish_rcv) = mpsc::unbounded_channel::<(IdentTopic, Vec<u8>)>();
+        // Tasks submitted through the API, for the main loop to dispatch
+        let (dispatch_sender, dispatch_rcv) = mpsc::unbounded_channel::<TaskRequest>();
+
+        let allowed_peers = PeerAllowlist::parse(&config.security.allowed_peers)?;
+        if let Some(peers) = &allowed_peers.0 {
+            info!("Only talking to the {} allowed peers", peers.len());
+        }
+
+        // Shared by the swarm and, once running, the `/metrics` endpoint
+        let metrics = Arc::new(Metrics::new()?);
+
+        // Direct requests stay open while the worker runs the task
+        let mut dispatch_config = RequestResponseConfig::default();
+        dispatch_config.set_request_timeout(max_task_timeout + Duration::from_secs(60));
+
+        let gossip = &config.gossip;
+        info!(
+            "Gossipsub: {} ms heartbeat, mesh of {} ({} to {}), {} heartbeats of history, {} gossiped",
+            gossip.heartbeat_ms,
+            gossip.mesh_n,
+            gossip.mesh_n_low,
+            gossip.mesh_n_high,
+            gossip.history_length,
+            gossip.history_gossip
+        );
+        let mut behaviour = OpenSkyBehaviour {
+            gossipsub: Gossipsub::new(
+                MessageAuthenticity::Signed(id_keys.clone()),
+                GossipsubConfigBuilder::default()
+                    .max_transmit_size(max_message_bytes)
+                    .heartbeat_interval(Duration::from_millis(gossip.heartbeat_ms))
+                    .mesh_n(gossip.mesh_n)
+                    .mesh_n_low(gossip.mesh_n_low)
+                    .mesh_n_high(gossip.mesh_n_high)
+                    // Can be at most mesh_n_low and half of mesh_n, so small
+                    // meshes need fewer than the default of 2
+                    .mesh_outbound_min(2.min(gossip.mesh_n / 2).min(gossip.mesh_n_low))
+                    .history_length(gossip.history_length)
+                    .history_gossip(gossip.history_gossip)
+                    .build()?,
+            )?,
+            mdns: if config.networking.mdns {
+                Some(Mdns::new(Default::default()).await?)
+            } else {
+                None
+            }
+            .into(),
+            kademlia: Kademlia::new(peer_id, MemoryStore::new(peer_id)),
+            ping: Ping::new(
+                PingConfig::new().with_interval(Duration::from_secs(config.networking.ping_interval_secs)),
+            ),
+            identify: Identify::new(
+                IdentifyConfig::new("/opensky/1.0.0".into(), id_keys.public())
+                    .with_agent_version(version().agent()),
+            ),
+            task_dispatch: RequestResponse::new(
+                OpenSkyCodec { max_message_bytes },
+                std::iter::once((OpenSkyProtocol, ProtocolSupport::Full)),
+                dispatch_config,
+            ),
+            autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+            relay_client,
+            response_sender,
+            node_updates: node_update_sender,
+            reputation: node.read().await.reputation.clone(),
+            local_node_id: peer_id.to_string(),
+            replay_guard: ReplayGuard::new(replay_window, nonce_cache_size),
+            metrics: metrics.clone(),
+            max_message_bytes,
+            replication_sender,
+            external_addr_sender,
+            publisher: publish_sender.clone(),
+            topic: topic.clone(),
+            pending_responses: HashMap::new(),
+            dispatched: HashMap::new(),
+            allowed_peers: allowed_peers.clone(),
+            prefer_local: config.limits.prefer_local,
+        };
+
+        for topic in &topics {
+            behaviour.gossipsub.subscribe(topic)?;
+        }
+
+        // Seed the DHT with the configured bootstrap peers
+        for addr in &config.networking.bootstrap {
+            match parse_bootstrap_addr(addr) {
+                Some((peer, addr)) => {
+                    info!("Adding bootstrap peer {} at {}", peer, addr);
+                    behaviour.kademlia.add_address(&peer, addr);
+                }
+                None => error!("Invalid bootstrap address (expected /.../p2p/<peer id>): {}", addr),
+            }
+        }
+
+        // Relays are public by definition, so they also make good AutoNAT servers
+        let relays: Vec<(PeerId, Multiaddr)> = config
+            .networking
+            .relays
+            .iter()
+            .filter_map(|addr| {
+                let relay = parse_bootstrap_addr(addr);
+                if relay.is_none() {
+                    error!("Invalid relay address (expected /.../p2p/<peer id>): {}", addr);
+                }
+                relay
+            })
+            .collect();
+        for (peer, addr) in &relays {
+            behaviour.autonat.add_server(*peer, Some(addr.clone()));
+        }
+
+        let mut swarm = SwarmBuilder::new(transport, behaviour, peer_id)
+            .executor(Box::new(|fut| {
+                tokio::spawn(fut);
+            }))
+            .build();
+
+        // Reconnect to peers remembered from earlier runs
+        for (peer, addrs) in known_peers {
+            for addr in &addrs {
+                swarm.behaviour_mut().kademlia.add_address(&peer, addr.clone());
+            }
+            if let Some(addr) = addrs.into_iter().next() {
+                debug!("Dialling known peer {} at {}", peer, addr);
+                if let Err(e) = swarm.dial(addr.with(Protocol::P2p(peer.into()))) {
+                    info!("Failed to dial known peer {}: {}", peer, e);
+                }
+            }
+        }
+
+        if let Err(e) = swarm.behaviour_mut().kademlia.bootstrap() {
+            info!("Skipping Kademlia bootstrap: {:?}", e);
+        }
+
+        // An executor given to the builder wins over the configured one
+        let executor: Arc<dyn TaskExecutor> = match (executor, config.executor) {
+            (Some(executor), _) => executor,
+            (None, ExecutorKind::Docker) => Arc::new(DockerExecutor::connect(config.limits.max_output_bytes)?),
+            (None, ExecutorKind::Mock) => {
+                warn!("Using the mock executor; tasks will not really run");
+                Arc::new(MockExecutor::new())
+            }
+        };
+
+        // Listen on every configured address
+        for addr in &config.networking.listen {
+            swarm.listen_on(addr.parse()?)?;
+        }
+
+        // Listening on a relay circuit reserves a slot there, so peers that
+        // can't dial us directly can reach us through it
+        for (peer, addr) in relays {
+            info!("Listening through relay {} at {}", peer, addr);
+            swarm.listen_on(addr.with(Protocol::P2pCircuit))?;
+        }
+
+        let handle = NodeHandle {
+            peer_id,
+            node: node.clone(),
+            publisher: publish_sender,
+            dispatcher: dispatch_sender,
+            topic,
+            topics,
+            shutdown: Arc::new(Notify::new()),
+        };
+        Ok(OpenSkyNode {
+            config,
+            console,
+            handlers,
+            id_keys,
+            swarm,
+            executor,
+            node,
+            metrics,
+            files_dir,
+            store,
+            state_path,
+            peer_store_path,
+            handle,
+            response_rcv,
+            replication_rcv,
+            external_addr_rcv,
+            publish_rcv,
+            dispatch_rcv,
+            node_update_rcv,
+        })
+    }
+}
+
+/// A running OpenSky node: the swarm, the HTTP API and the task and storage
+/// services. Built with [`OpenSkyNode::builder`]; nothing happens until
+/// [`OpenSkyNode::run`] is awaited.
+pub struct OpenSkyNode {
+    config: NodeConfig,
+    console: bool,
+    handlers: Vec<Arc<dyn CommandHandler>>,
+    id_keys: identity::Keypair,
+    swarm: Swarm<OpenSkyBehaviour>,
+    executor: Arc<dyn TaskExecutor>,
+    node: Arc<RwLock<NodeState>>,
+    metrics: Arc<Metrics>,
+    files_dir: PathBuf,
+    store: Arc<dyn BlobStore>,
+    state_path: PathBuf,
+    peer_store_path: PathBuf,
+    handle: NodeHandle,
+    response_rcv: mpsc::UnboundedReceiver<(String, OpenSkyCommand)>,
+    replication_rcv: mpsc::UnboundedReceiver<String>,
+    external_addr_rcv: mpsc::UnboundedReceiver<Multiaddr>,
+    publish_rcv: mpsc::UnboundedReceiver<(IdentTopic, Vec<u8>)>,
+    dispatch_rcv: mpsc::UnboundedReceiver<TaskRequest>,
+    node_update_rcv: mpsc::UnboundedReceiver<NodeUpdate>,
+}
+
+/// An amount of each resource a node shares
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resources {
+    pub cpu_cores: u8,
+    pub memory_mb: u64,
+    pub storage_bytes: u64,
+}
+
+/// A snapshot of a node's resources and activity
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub node_id: String,
+    /// What's free for new work: `total - reserved`, capped by idle host capacity
+    pub available: Resources,
+    /// Configured capacity offered to the network
+    pub total: Resources,
+    /// Held by running tasks and stored files
+    pub reserved: Resources,
+    pub bandwidth_mbps: u32,
+    pub peers: usize,
+    pub tasks: usize,
+    pub files: usize,
+    pub uptime_secs: u64,
+    /// RFC 3339 time the node started
+    pub started_at: String,
+}
+
+/// Submits tasks to and queries the state of an [`OpenSkyNode`]. Cheap to
+/// clone and usable from any task, before or while the node runs.
+#[derive(Clone)]
+pub struct NodeHandle {
+    peer_id: PeerId,
+    node: Arc<RwLock<NodeState>>,
+    publisher: mpsc::UnboundedSender<(IdentTopic, Vec<u8>)>,
+    dispatcher: mpsc::UnboundedSender<TaskRequest>,
+    // Where `publish` sends; the first of `topics`, or in a handler's
+    // context the topic the command arrived on
+    topic: IdentTopic,
+    topics: Vec<IdentTopic>,
+    shutdown: Arc<Notify>,
+}
+
+impl NodeHandle {
+    /// This node's peer id
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Current resources and activity
+    pub async fn status(&self) -> NodeStatus {
+        let node = self.node.read().await;
+        NodeStatus {
+            node_id: node.node_id.clone(),
+            available: node.available(),
+            total: node.total(),
+            reserved: node.reserved(),
+            bandwidth_mbps: node.available_bandwidth,
+            peers: node.peers.len(),
+            tasks: node.tasks.len(),
+            files: node.stored_files.len(),
+            uptime_secs: node.uptime_secs(),
+            started_at: node.started_at_rfc3339(),
+        }
+    }
+
+    /// Peers we're connected to
+    pub async fn peers(&self) -> Vec<String> {
+        self.node.read().await.peers.iter().cloned().collect()
+    }
+
+    // Check a submission and turn it into the request workers receive, along
+    // with the topic it runs in
+    fn task_request(&self, task: TaskSubmission) -> Result<(TaskRequest, IdentTopic), String> {
+        if task.docker_image.is_empty() || task.cpu_cores == 0 || task.memory_mb == 0 || task.command.is_empty() {
+            return Err(
+                "docker_image and command must be non-empty and cpu_cores and memory_mb greater than 0".into(),
+            );
+        }
+        validate_task_env(&task.env, task.working_dir.as_deref())?;
+        validate_task_inputs(&task.inputs)?;
+        validate_task_outputs(&task.outputs)?;
+        if task.redundancy > MAX_TASK_REDUNDANCY {
+            return Err(format!("redundancy can be at most {}", MAX_TASK_REDUNDANCY));
+        }
+        let topic = match &task.topic {
+            Some(name) => self.find_topic(name).ok_or_else(|| format!("not subscribed to topic {}", name))?,
+            None => self.topic.clone(),
+        };
+
+        let request = TaskRequest {
+            task_id: task.task_id.clone(),
+            docker_image: task.docker_image,
+            cpu_cores: task.cpu_cores,
+            memory_mb: task.memory_mb,
+            command: task.command,
+            requester_id: self.peer_id.to_string(),
+            timeout_secs: task.timeout_secs,
+            env: task.env,
+            working_dir: task.working_dir,
+            platform: task.platform,
+            gpus: task.gpus,
+            redundancy: task.redundancy,
+            inputs: task.inputs,
+            outputs: task.outputs,
+        };
+        Ok((request, topic))
+    }
+
+    /// Queue a task for the best-suited worker, returning its task_id
+    pub async fn submit_task(&self, task: TaskSubmission) -> Result<String, String> {
+        let (request, topic) = self.task_request(task)?;
+        let task_id = request.task_id.clone();
+        {
+            let mut node = self.node.write().await;
+            node.set_task_state(&task_id, TaskStatus::Queued, None);
+            node.submitted_tasks.insert(task_id.clone(), SubmittedTask {
+                request: request.clone(),
+                topic,
+                attempts: 0,
+                tried: HashSet::new(),
+            });
+        }
+        let _ = self.dispatcher.send(request);
+        info!(task_id = %task_id, "Submitted task: {}", task_id);
+        Ok(task_id)
+    }
+
+    /// The known workers that could run a task right now, best first, without
+    /// submitting it. Empty means it would only be broadcast and wait.
+    pub async fn task_candidates(&self, task: TaskSubmission) -> Result<Vec<String>, String> {
+        let (request, topic) = self.task_request(task)?;
+        Ok(task_candidates(&self.node.read().await, &request, topic.hash().as_str()))
+    }
+
+    /// What we know about a task, asking its worker for an update if the task
+    /// hasn't finished yet
+    pub async fn task_status(&self, task_id: &str) -> Option<TaskState> {
+        let state = self.node.read().await.task_states.get(task_id).cloned()?;
+        if !state.status.is_terminal() {
+            self.publish(&OpenSkyCommand::TaskStatusRequest {
+                task_id: task_id.to_string(),
+                requester_id: self.peer_id.to_string(),
+            });
+        }
+        Some(state)
+    }
+
+    /// Wait up to `timeout` for a task we submitted to finish, returning its
+    /// final state, or `None` if no result arrived in time
+    pub async fn wait_for_task(&self, task_id: &str, timeout: Duration) -> Option<TaskState> {
+        let (waiter, finished) = oneshot::channel();
+        {
+            let mut node = self.node.write().await;
+            match node.task_states.get(task_id) {
+                Some(state) if state.status.is_terminal() => return Some(state.clone()),
+                _ => node.task_waiters.insert(task_id.to_string(), waiter),
+            };
+        }
+        match tokio::time::timeout(timeout, finished).await {
+            Ok(Ok(state)) => Some(state),
+            _ => {
+                self.node.write().await.task_waiters.remove(task_id);
+                None
+            }
+        }
+    }
+
+    /// Cancel a task we submitted; the worker running it stops the container
+    /// and reports a failed TaskResult
+    pub async fn cancel_task(&self, task_id: &str) {
+        // A cancelled task must not be retried elsewhere
+        self.node.write().await.submitted_tasks.remove(task_id);
+        self.publish(&OpenSkyCommand::TaskCancel {
+            task_id: task_id.to_string(),
+            requester_id: self.peer_id.to_string(),
+        });
+        info!(task_id, "Requested cancellation of task: {}", task_id);
+    }
+
+    /// Broadcast a command on the node's topic
+    pub fn publish(&self, command: &OpenSkyCommand) {
+        let json = serde_json::to_vec(command).expect("Failed to serialize");
+        let _ = self.publisher.send((self.topic.clone(), json));
+    }
+
+    /// Broadcast a command on one of the topics the node has joined
+    pub fn publish_to(&self, topic: &str, command: &OpenSkyCommand) -> Result<(), String> {
+        let topic = self.find_topic(topic).ok_or_else(|| format!("not subscribed to topic {}", topic))?;
+        let json = serde_json::to_vec(command).expect("Failed to serialize");
+        let _ = self.publisher.send((topic, json));
+        Ok(())
+    }
+
+    /// The topics the node has joined
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.iter().map(|topic| topic.hash().into_string()).collect()
+    }
+
+    fn find_topic(&self, name: &str) -> Option<IdentTopic> {
+        self.topics.iter().find(|topic| topic.hash().as_str() == name).cloned()
+    }
+
+    /// Follow the node's activity as it happens
+    pub async fn events(&self) -> broadcast::Receiver<RecordedEvent> {
+        self.node.write().await.events.subscribe()
+    }
+
+    /// Ask the node to stop; `run` returns once in-flight tasks finish and
+    /// state is saved
+    pub fn shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+}
+
+impl OpenSkyNode {
+    /// Start configuring a node with the built-in defaults
+    pub fn builder() -> OpenSkyNodeBuilder {
+        OpenSkyNodeBuilder {
+            config: NodeConfig::default(),
+            data_dir: None,
+            console: false,
+            handlers: Vec::new(),
+            executor: None,
+            memory_transport: false,
+        }
+    }
+
+    /// A handle for submitting tasks and querying state
+    pub fn handle(&self) -> NodeHandle {
+        self.handle.clone()
+    }
+
+    /// Serve the API and take part in the network until
+    /// [`NodeHandle::shutdown`] is called or the console sends `quit`
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        let OpenSkyNode {
+            config,
+            console,
+            handlers,
+            id_keys,
+            mut swarm,
+            executor,
+            node,
+            metrics,
+            files_dir,
+            store,
+            state_path,
+            peer_store_path,
+            handle,
+            mut response_rcv,
+            mut replication_rcv,
+            mut external_addr_rcv,
+            mut publish_rcv,
+            mut dispatch_rcv,
+            mut node_update_rcv,
+        } = self;
+        let peer_id = handle.peer_id;
+        let topic = handle.topic.clone();
+        let publish_sender = handle.publisher.clone();
+        let max_bandwidth_mbps = config.resources.bandwidth_mbps;
+        let max_concurrent_tasks = config.limits.max_concurrent_tasks;
+        let max_task_timeout = Duration::from_secs(config.limits.task_timeout_secs);
+        let max_task_retries = config.limits.task_max_retries;
+        let replication_factor = config.limits.replication_factor;
+        let api_addr = config.networking.api_addr;
+        let image_allowlist = config.security.image_allowlist.clone();
+        if image_allowlist.is_empty() {
+            info!("Image allowlist is empty; this node will reject every task");
+        }
+        let api_key: Option<Arc<str>> = config.security.api_key.as_deref().map(Arc::from);
+        if api_key.is_none() {
+            warn!("OPENSKY_API_KEY is not set; the HTTP API is open to anyone who can reach it");
+        }
+
+        // Storage transfers share the advertised bandwidth
+        let bandwidth = Arc::new(Bandwidth::new(max_bandwidth_mbps, config.limits.max_concurrent_transfers));
+
+        // Create a clone of node for the web API
+        let node_for_api = node.clone();
+        let bandwidth_for_api = bandwidth.clone();
+        let files_dir_for_api = files_dir.clone();
+
+        // Set up the web API
+        let node_routes = warp::path("api")
+            .and(warp::path("node"))
+            .and(warp::get())
+            .then(move || {
+                let node_for_api = node_for_api.clone();
+                let bandwidth_for_api = bandwidth_for_api.clone();
+                let files_dir_for_api = files_dir_for_api.clone();
+                async move {
+                    let disk_free = disk_free_bytes(&files_dir_for_api);
+                    let node = node_for_api.read().await;
+                    warp::reply::json(&serde_json::json!({
+                        "node_id": node.node_id,
+                        "resources": {
+                            "cpu_cores": node.available_cpu,
+                            "memory_mb": node.available_memory,
+                            "storage_bytes": node.available_storage(),
+                            "storage": format_gb(node.available_storage()),
+                            "bandwidth_mbps": node.available_bandwidth
+                        },
+                        "total": node.total(),
+                        "reserved": node.reserved(),
+                        "usage": {
+                            "cpu_percent": node.cpu_usage_percent,
+                            "free_memory_mb": node.free_memory_mb
+                        },
+                        // Offered storage left, versus what the disk itself has free
+                        "storage_free": {
+                            "logical_bytes": node.available_storage(),
+                            "physical_bytes": disk_free
+                        },
+                        "throughput": {
+                            "sent_bytes_per_sec": bandwidth_for_api.sent_per_sec.load(Ordering::Relaxed),
+                            "received_bytes_per_sec": bandwidth_for_api.received_per_sec.load(Ordering::Relaxed),
+                            "limit_mbps": node.available_bandwidth
+                        },
+                        "transfers": {
+                            "active": bandwidth_for_api.active_transfers(),
+                            "incoming": node.incoming_transfers.len(),
+                            "queued": bandwidth_for_api.queued_transfers.load(Ordering::Relaxed),
+                            "max": bandwidth_for_api.max_transfers
+                        },
+                        "peers": node.peers.len(),
+                        "tasks": node.tasks.len(),
+                        "files": node.stored_files.len(),
+                        "draining": node.draining,
+                        "listen_addresses": node.listen_addresses,
+                        "uptime_secs": node.uptime_secs(),
+                        "started_at": node.started_at_rfc3339()
+                    }))
+                }
+            });
+
+        // List connected peers with their latest ping round-trip time
+        let node_for_peers = node.clone();
+        let peers_routes = warp::path("api")
+            .and(warp::path("peers"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move || {
+                let node_for_peers = node_for_peers.clone();
+                async move {
+                    let node = node_for_peers.read().await;
+                    let peers: Vec<_> = node
+                        .peers
+                        .iter()
+                        .map(|peer| {
+                            let connection = node.peer_connections.get(peer);
+                            serde_json::json!({
+                                "peer_id": peer,
+                                "connected": connection.is_some(),
+                                "addresses": connection.map_or(&[][..], |c| &c.addresses[..]),
+                                "connected_since": connection
+                                    .map(|c| humantime::format_rfc3339_seconds(c.connected_since).to_string()),
+                                "discovered_via": node.peer_discovery.get(peer),
+                                "last_rtt_ms": node.peer_rtts.get(peer).map(|rtt| rtt.as_secs_f64() * 1000.0),
+                                "reputation": node.reputation.lock().unwrap().stats_json(peer),
+                                "identity": node.peer_identities.get(peer),
+                                "advertised_resources": node.network_resources.get(peer).map(|record| serde_json::json!({
+                                    "cpu_cores": record.cpu_cores,
+                                    "memory_mb": record.memory_mb,
+                                    "storage_gb": record.storage_gb,
+                                    "bandwidth_mbps": record.bandwidth_mbps,
+                                    "arch": record.capabilities.arch,
+                                    "os": record.capabilities.os,
+                                    "gpus": record.capabilities.gpus,
+                                    "last_seen_secs": record.last_seen.elapsed().as_secs()
+                                }))
+                            })
+                        })
+                        .collect();
+                    warp::reply::json(&serde_json::json!({ "peers": peers }))
+                }
+            });
+
+        // Tasks running across the network: ours plus every live census
+        let node_for_cluster_tasks = node.clone();
+        let cluster_tasks_routes = warp::path("api")
+            .and(warp::path("cluster"))
+            .and(warp::path("tasks"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move || {
+                let node_for_cluster_tasks = node_for_cluster_tasks.clone();
+                async move {
+                    let node = node_for_cluster_tasks.read().await;
+                    let mut tasks: Vec<_> = node
+                        .local_tasks()
+                        .into_iter()
+                        .map(|task| {
+                            serde_json::json!({
+                                "task_id": task.task_id,
+                                "status": task.status,
+                                "node_id": node.node_id,
+                                "last_seen_secs": 0
+                            })
+                        })
+                        .collect();
+                    let live = node
+                        .cluster_tasks
+                        .iter()
+                        .filter(|(_, census)| census.last_seen.elapsed() < RESOURCE_OFFER_TTL);
+                    for (node_id, census) in live {
+                        for task in &census.tasks {
+                            tasks.push(serde_json::json!({
+                                "task_id": task.task_id,
+                                "status": task.status,
+                                "node_id": node_id,
+                                "last_seen_secs": census.last_seen.elapsed().as_secs()
+                            }));
+                        }
+                    }
+                    warp::reply::json(&serde_json::json!({ "tasks": tasks }))
+                }
+            });
+
+        // Cluster-wide capacity: our own availability plus every live offer
+        let node_for_cluster = node.clone();
+        let cluster_routes = warp::path("api")
+            .and(warp::path("cluster"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move || {
+                let node_for_cluster = node_for_cluster.clone();
+                async move {
+                    let node = node_for_cluster.read().await;
+                    let mut total = (
+                        node.available_cpu as u32,
+                        node.available_memory,
+                        node.available_storage() / GIB,
+                        node.available_bandwidth as u64,
+                    );
+                    let mut nodes = vec![serde_json::json!({
+                        "node_id": node.node_id,
+                        "local": true,
+                        "cpu_cores": node.available_cpu,
+                        "memory_mb": node.available_memory,
+                        "storage_gb": node.available_storage() / GIB,
+                        "bandwidth_mbps": node.available_bandwidth,
+                        "arch": node.capabilities.arch,
+                        "os": node.capabilities.os,
+                        "gpus": node.capabilities.gpus,
+                        "last_seen_secs": 0
+                    })];
+                    // The sweep only runs periodically, so skip offers that have
+                    // outlived their TTL in the meantime
+                    let live = node
+                        .network_resources
+                        .iter()
+                        .filter(|(_, record)| record.last_seen.elapsed() < RESOURCE_OFFER_TTL);
+                    for (node_id, record) in live {
+                        total.0 += record.cpu_cores as u32;
+                        total.1 += record.memory_mb;
+                        total.2 += record.storage_gb as u64;
+                        total.3 += record.bandwidth_mbps as u64;
+                        nodes.push(serde_json::json!({
+                            "node_id": node_id,
+                            "local": false,
+                            "cpu_cores": record.cpu_cores,
+                            "memory_mb": record.memory_mb,
+                            "storage_gb": record.storage_gb,
+                            "bandwidth_mbps": record.bandwidth_mbps,
+                            "arch": record.capabilities.arch,
+                            "os": record.capabilities.os,
+                            "gpus": record.capabilities.gpus,
+                            "protocol_version": record.protocol_version,
+                            "last_seen_secs": record.last_seen.elapsed().as_secs()
+                        }));
+                    }
+                    warp::reply::json(&serde_json::json!({
+                        "total": {
+                            "nodes": nodes.len(),
+                            "cpu_cores": total.0,
+                            "memory_mb": total.1,
+                            "storage_gb": total.2,
+                            "bandwidth_mbps": total.3
+                        },
+                        "nodes": nodes
+                    }))
+                }
+            });
+
+        // Let operators change the resources we offer without a restart. This
+        // only works with an API key set, since it changes what the node shares.
+        let announce_now = Arc::new(Notify::new());
+        let admin_enabled = api_key.is_some();
+        let node_for_limits = node.clone();
+        let bandwidth_for_limits = bandwidth.clone();
+        let announce_for_limits = announce_now.clone();
+        let limits_routes = warp::path("api")
+            .and(warp::path("node"))
+            .and(warp::path("resources"))
+            .and(warp::path::end())
+            .and(warp::put())
+            .and(warp::body::json())
+            .then(move |limits: ResourceLimits| {
+                let node_for_limits = node_for_limits.clone();
+                let bandwidth_for_limits = bandwidth_for_limits.clone();
+                let announce_for_limits = announce_for_limits.clone();
+                async move {
+                    if !admin_enabled {
+                        return json_error("set OPENSKY_API_KEY to change resource limits", StatusCode::FORBIDDEN);
+                    }
+                    if limits.cpu_percent.map_or(false, |p| p == 0 || p > 100) {
+                        return json_error("cpu_percent must be between 1 and 100", StatusCode::BAD_REQUEST);
+                    }
+                    let mut node = node_for_limits.write().await;
+                    node.set_limits(&limits);
+                    if let Some(bandwidth_mbps) = limits.bandwidth_mbps {
+                        bandwidth_for_limits.set_limit(bandwidth_mbps);
+                    }
+                    info!(
+                        "Resource limits changed: {} CPU cores, {}, {} Mbps",
+                        node.total_cpu, format_gb(node.total_storage), node.available_bandwidth
+                    );
+                    announce_for_limits.notify_one();
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({
+                            "cpu_cores": node.total_cpu,
+                            "storage_bytes": node.total_storage,
+                            "storage": format_gb(node.total_storage),
+                            "bandwidth_mbps": node.available_bandwidth
+                        })),
+                        StatusCode::OK,
+                    )
+                }
+            });
+
+        // Say whether a task could be scheduled, and where, without running it
+        let handle_for_validate = handle.clone();
+        let task_validate_routes = warp::path("api")
+            .and(warp::path("tasks"))
+            .and(warp::path("validate"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::body::json())
+            .then(move |task: TaskSubmission| {
+                let handle_for_validate = handle_for_validate.clone();
+                async move {
+                    match handle_for_validate.task_candidates(task).await {
+                        Ok(candidates) => warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({
+                                "schedulable": !candidates.is_empty(),
+                                "candidates": candidates
+                            })),
+                            StatusCode::OK,
+                        ),
+                        Err(e) => json_error(&e, StatusCode::BAD_REQUEST),
+                    }
+                }
+            });
+
+        // Accept tasks over HTTP, hand them to the main loop to dispatch and
+        // answer once they finish. A task nobody answers for within the submit
+        // timeout is cancelled and reported as a 504.
+        let handle_for_submit = handle.clone();
+        let submit_timeout = Duration::from_secs(config.limits.submit_timeout_secs);
+        let task_routes = warp::path("api")
+            .and(warp::path("tasks"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::body::json())
+            .then(move |task: TaskSubmission| {
+                let handle_for_submit = handle_for_submit.clone();
+                async move {
+                    let task_id = match handle_for_submit.submit_task(task).await {
+                        Ok(task_id) => task_id,
+                        Err(e) => return json_error(&e, StatusCode::BAD_REQUEST),
+                    };
+                    match handle_for_submit.wait_for_task(&task_id, submit_timeout).await {
+                        Some(state) => warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({
+                                "task_id": task_id,
+                                "status": state.status,
+                                "node_id": state.node_id,
+                                "attempts": state.attempts,
+                                "quorum": state.quorum,
+                                "output_files": state.output_files
+                            })),
+                            StatusCode::OK,
+                        ),
+                        None => {
+                            info!(task_id = %task_id, "No worker responded to task {} in time", task_id);
+                            handle_for_submit.cancel_task(&task_id).await;
+                            handle_for_submit.node.write().await.set_task_state(&task_id, TaskStatus::Failed, None);
+                            warp::reply::with_status(
+                                warp::reply::json(&serde_json::json!({
+                                    "error": "no worker responded",
+                                    "task_id": task_id
+                                })),
+                                StatusCode::GATEWAY_TIMEOUT,
+                            )
+                        }
+                    }
+                }
+            });
+
+        // Tasks this node has run, newest first
+        let node_for_history = node.clone();
+        let task_history_routes = warp::path("api")
+            .and(warp::path("tasks"))
+            .and(warp::path("history"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<HistoryQuery>())
+            .then(move |query: HistoryQuery| {
+                let node_for_history = node_for_history.clone();
+                async move {
+                    let node = node_for_history.read().await;
+                    warp::reply::json(&node.recent_task_history(query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT)))
+                }
+            });
+
+        // Report what we know about a task
+        let handle_for_status = handle.clone();
+        let task_status_routes = warp::path("api")
+            .and(warp::path("tasks"))
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move |task_id: String| {
+                let handle_for_status = handle_for_status.clone();
+                async move {
+                    match handle_for_status.task_status(&task_id).await {
+                        Some(state) => warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({
+                                "task_id": task_id,
+                                "status": state.status,
+                                "node_id": state.node_id,
+                                "attempts": state.attempts,
+                                "quorum": state.quorum,
+                                "output_files": state.output_files
+                            })),
+                            StatusCode::OK,
+                        ),
+                        None => warp::reply::with_status(
+                            warp::reply::json(&serde_json::json!({
+                                "task_id": task_id,
+                                "status": TaskStatus::Unknown
+                            })),
+                            StatusCode::NOT_FOUND,
+                        ),
+                    }
+                }
+            });
+
+        // Cancel a task we submitted
+        let handle_for_cancel = handle.clone();
+        let task_cancel_routes = warp::path("api")
+            .and(warp::path("tasks"))
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(warp::delete())
+            .then(move |task_id: String| {
+                let handle_for_cancel = handle_for_cancel.clone();
+                async move {
+                    handle_for_cancel.cancel_task(&task_id).await;
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({ "task_id": task_id })),
+                        StatusCode::ACCEPTED,
+                    )
+                }
+            });
+
+        // Expose the resources advertised across the network
+        let node_for_network = node.clone();
+        let network_routes = warp::path("api")
+            .and(warp::path("resources"))
+            .and(warp::path("network"))
+            .and(warp::get())
+            .then(move || {
+                let node_for_network = node_for_network.clone();
+                async move {
+                    let node = node_for_network.read().await;
+                    let mut total = (0u32, 0u64, 0u64, 0u64);
+                    let mut nodes = Vec::new();
+                    for (node_id, record) in &node.network_resources {
+                        total.0 += record.cpu_cores as u32;
+                        total.1 += record.memory_mb;
+                        total.2 += record.storage_gb as u64;
+                        total.3 += record.bandwidth_mbps as u64;
+                        nodes.push(serde_json::json!({
+                            "node_id": node_id,
+                            "cpu_cores": record.cpu_cores,
+                            "memory_mb": record.memory_mb,
+                            "storage_gb": record.storage_gb,
+                            "bandwidth_mbps": record.bandwidth_mbps,
+                            "protocol_version": record.protocol_version,
+                            "last_seen_secs": record.last_seen.elapsed().as_secs()
+                        }));
+                    }
+                    warp::reply::json(&serde_json::json!({
+                        "total": {
+                            "cpu_cores": total.0,
+                            "memory_mb": total.1,
+                            "storage_gb": total.2,
+                            "bandwidth_mbps": total.3
+                        },
+                        "nodes": nodes
+                    }))
+                }
+            });
+
+        // Copies uploaded files to other nodes
+        let replicator = Replicator {
+            node: node.clone(),
+            store: store.clone(),
+            files_dir: files_dir.clone(),
+            publisher: publish_sender.clone(),
+            topic: topic.clone(),
+            replication_factor,
+            bandwidth: bandwidth.clone(),
+        };
+
+        // Accept file uploads
+        let replicator_for_upload = replicator.clone();
+        let upload_routes = warp::path("api")
+            .and(warp::path("files"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::multipart::form().max_length(MAX_UPLOAD_BYTES))
+            .and_then(move |form: FormData| upload_file(form, replicator_for_upload.clone()));
+
+        // List stored files with their replica counts
+        let node_for_files = node.clone();
+        let files_routes = warp::path("api")
+            .and(warp::path("files"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move || {
+                let node_for_files = node_for_files.clone();
+                async move {
+                    let node = node_for_files.read().await;
+                    let now = unix_secs();
+                    let files: Vec<_> = node
+                        .stored_files
+                        .iter()
+                        .map(|file_id| {
+                            let replicas = node.file_replicas.get(file_id);
+                            // Files held for a peer show what that peer uses here in all
+                            let owner = node.peer_files.get(file_id).map(|file| &file.owner);
+                            serde_json::json!({
+                                "file_id": file_id,
+                                "sha256": file_id,
+                                "size_bytes": node.file_sizes.get(file_id),
+                                "replica_count": replicas.map_or(0, |r| r.len()),
+                                "replicas": replicas,
+                                "ttl_remaining_secs": node.file_ttls.get(file_id).map(|ttl| ttl.remaining_secs(now)),
+                                "stored_for": owner,
+                                "stored_for_usage_bytes": owner.map(|owner| node.peer_storage_used(owner)),
+                                "stored_for_quota_bytes": owner.and(node.max_storage_per_peer)
+                            })
+                        })
+                        .collect();
+                    warp::reply::json(&files)
+                }
+            });
+
+        // Serve stored files back to clients, or files stored on peers
+        let handle_for_download = handle.clone();
+        let store_for_download = store.clone();
+        let bandwidth_for_download = bandwidth.clone();
+        let download_routes = warp::path("api")
+            .and(warp::path("files"))
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::query::<DownloadQuery>())
+            .and_then(move |file_id: String, range: Option<String>, query: DownloadQuery| {
+                download_file(
+                    file_id,
+                    range,
+                    query,
+                    handle_for_download.clone(),
+                    store_for_download.clone(),
+                    bandwidth_for_download.clone(),
+                )
+            });
+
+        // Recent node activity, optionally only events after `?since=<seq>`
+        let node_for_events = node.clone();
+        let events_routes = warp::path("api")
+            .and(warp::path("events"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(warp::query::<EventsQuery>())
+            .then(move |query: EventsQuery| {
+                let node_for_events = node_for_events.clone();
+                async move {
+                    let node = node_for_events.read().await;
+                    warp::reply::json(&node.events.since(query.since.unwrap_or(0)))
+                }
+            });
+
+        // Live event stream for monitoring dashboards
+        let node_for_event_stream = node.clone();
+        let event_stream_routes = warp::path("api")
+            .and(warp::path("events"))
+            .and(warp::path("ws"))
+            .and(warp::path::end())
+            .and(warp::ws())
+            .then(move |ws: Ws| {
+                let node_for_event_stream = node_for_event_stream.clone();
+                async move {
+                    let events = node_for_event_stream.read().await.events.subscribe();
+                    ws.on_upgrade(move |socket| stream_events(socket, events))
+                }
+            });
+
+        // Live output of a task running here
+        let node_for_logs = node.clone();
+        let executor_for_logs = executor.clone();
+        let task_logs_routes = warp::path("api")
+            .and(warp::path("tasks"))
+            .and(warp::path::param::<String>())
+            .and(warp::path("logs"))
+            .and(warp::path("ws"))
+            .and(warp::path::end())
+            .and(warp::ws())
+            .then(move |task_id: String, ws: Ws| {
+                let node_for_logs = node_for_logs.clone();
+                let executor_for_logs = executor_for_logs.clone();
+                async move {
+                    let not_running = || -> Box<dyn warp::Reply> {
+                        Box::new(json_error("task is not running on this node", StatusCode::NOT_FOUND))
+                    };
+                    if !node_for_logs.read().await.running_tasks.contains_key(&task_id) {
+                        return not_running();
+                    }
+                    match executor_for_logs.follow_logs(&task_id).await {
+                        Some(logs) => Box::new(ws.on_upgrade(move |socket| stream_task_logs(socket, logs))),
+                        None => not_running(),
+                    }
+                }
+            });
+
+        // Which build this is
+        let version_routes = warp::path("api")
+            .and(warp::path("version"))
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(|| warp::reply::json(&version()));
+
+        // Prometheus scrape endpoint
+        let metrics_for_scrape = metrics.clone();
+        let node_for_metrics = node.clone();
+        let metrics_routes = warp::path("metrics")
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move || {
+                let metrics_for_scrape = metrics_for_scrape.clone();
+                let node_for_metrics = node_for_metrics.clone();
+                async move {
+                    let body = metrics_for_scrape.render(&node_for_metrics.read().await);
+                    warp::reply::with_header(body, header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                }
+            });
+
+        // Liveness and readiness probes for orchestrators
+        let probes = Arc::new(Probes::default());
+        probes.heartbeat.store(unix_secs(), Ordering::Relaxed);
+        let probes_for_health = probes.clone();
+        let health_routes = warp::path("health")
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(move || warp::reply::with_status("", probe_status(probes_for_health.is_alive())));
+        let probes_for_ready = probes.clone();
+        let ready_routes = warp::path("ready")
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(move || warp::reply::with_status("", probe_status(probes_for_ready.is_ready())));
+
+        // Toggle drain mode for maintenance
+        let node_for_drain = node.clone();
+        let probes_for_drain = probes.clone();
+        let announce_for_drain = announce_now.clone();
+        let drain_routes = warp::path("api")
+            .and(warp::path("node"))
+            .and(warp::path("drain"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .then(move || {
+                let node_for_drain = node_for_drain.clone();
+                let probes_for_drain = probes_for_drain.clone();
+                let announce_for_drain = announce_for_drain.clone();
+                async move {
+                    let draining = toggle_drain(&node_for_drain, &probes_for_drain).await;
+                    announce_for_drain.notify_one();
+                    warp::reply::json(&serde_json::json!({ "draining": draining }))
+                }
+            });
+
+        // Everything but the probes needs the API key, if one is set
+        let api_routes = require_api_key(api_key).and(
+            node_routes
+                .or(limits_routes)
+                .or(drain_routes)
+                .or(peers_routes)
+                .or(cluster_routes)
+                .or(cluster_tasks_routes)
+                .or(task_validate_routes)
+                .or(task_routes)
+                .or(task_history_routes)
+                .or(task_status_routes)
+                .or(task_cancel_routes)
+                .or(task_logs_routes)
+                .or(network_routes)
+                .or(upload_routes)
+                .or(files_routes)
+                .or(download_routes)
+                .or(events_routes)
+                .or(event_stream_routes)
+                .or(version_routes)
+                .or(metrics_routes),
+        );
+
+        // Start the web server, over HTTPS if we have a certificate
+        let routes = health_routes
+            .or(ready_routes)
+            .or(api_routes)
+            .recover(handle_rejection);
+        let scheme = match config.security.tls_files()? {
+            Some((cert, key)) => {
+                tokio::spawn(warp::serve(routes).tls().cert_path(cert).key_path(key).run(api_addr));
+                "https"
+            }
+            None => {
+                tokio::spawn(warp::serve(routes).run(api_addr));
+                "http"
+            }
+        };
+
+        // The built-in handlers come first, then any registered on the builder
+        let mut command_handlers: Vec<Arc<dyn CommandHandler>> = vec![
+            Arc::new(ResourceHandler),
+            Arc::new(TaskHandler {
+                task_slots: Arc::new(Semaphore::new(max_concurrent_tasks)),
+                max_concurrent_tasks,
+                max_task_timeout,
+                max_task_retries,
+                image_allowlist,
+                dedup_window: Duration::from_secs(config.limits.task_dedup_window_secs),
+                strict_outputs: config.limits.strict_outputs,
+            }),
+            Arc::new(StorageHandler),
+        ];
+        command_handlers.extend(handlers);
+        let mut context = NodeContext {
+            handle: handle.clone(),
+            executor,
+            files_dir: files_dir.clone(),
+            store: store.clone(),
+            metrics: metrics.clone(),
+            bandwidth: bandwidth.clone(),
+        };
+
+        // Process incoming commands
+        tokio::spawn(async move {
+            while let Some((topic, command)) = response_rcv.recv().await {
+                context.handle.topic = IdentTopic::new(topic);
+                dispatch_command(&command_handlers, &command, &context).await;
+            }
+        });
+
+        // Announce our resources now and then periodically
+        let announce_interval = Duration::from_secs(config.networking.announce_interval_secs);
+        let topics_for_announce = handle.topics.clone();
+        let publisher = publish_sender.clone();
+        let node_for_announce = node.clone();
+        let probes_for_announce = probes.clone();
+        let announce_for_announce = announce_now.clone();
+        tokio::spawn(async move {
+            let node = node_for_announce;
+            loop {
+                let (resource_offer, census) = {
+                    let node = node.read().await;
+                    (node.resource_offer(), node.task_census())
+                };
+                let json = serde_json::to_vec(&resource_offer).expect("Failed to serialize");
+                if topics_for_announce
+                    .iter()
+                    .any(|topic| publisher.send((topic.clone(), json.clone())).is_err())
+                {
+                    break;
+                }
+                // Tasks aren't tied to a topic, so one census per round will do
+                let json = serde_json::to_vec(&census).expect("Failed to serialize");
+                let _ = publisher.send((topics_for_announce[0].clone(), json));
+                probes_for_announce.announced.store(true, Ordering::Relaxed);
+
+                // Announce early when the limits change
+                tokio::select! {
+                    _ = tokio::time::sleep(with_jitter(announce_interval)) => {}
+                    _ = announce_for_announce.notified() => {}
+                }
+            }
+        });
+
+        // Re-replicate files whose holders went away
+        tokio::spawn(async move {
+            while let Some(file_id) = replication_rcv.recv().await {
+                let replicator = replicator.clone();
+                tokio::spawn(async move { replicator.rereplicate(&file_id).await });
+            }
+        });
+
+        // Sample real host load so announcements reflect current capacity
+        let node_for_sampler = node.clone();
+        tokio::spawn(async move {
+            let mut system = System::new();
+            loop {
+                system.refresh_cpu();
+                system.refresh_memory();
+                // CPU usage is measured between two refreshes
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                system.refresh_cpu();
+
+                let cpu_usage = system.global_cpu_info().cpu_usage();
+                let host_cores = system.cpus().len() as f32;
+                let idle_cores = (host_cores * (100.0 - cpu_usage) / 100.0).max(0.0) as u8;
+                let free_memory_mb = system.available_memory() / (1024 * 1024);
+
+                let mut node = node_for_sampler.write().await;
+                node.cpu_usage_percent = cpu_usage;
+                node.idle_cpu_cores = idle_cores;
+                node.free_memory_mb = free_memory_mb;
+                node.refresh_available();
+            }
+        });
+
+        // Close the throughput window every second
+        let bandwidth_for_meter = bandwidth.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                bandwidth_for_meter.roll_window();
+            }
+        });
+
+        // Abandon incoming transfers that stopped receiving chunks, including
+        // offers the uploader never took up
+        let node_for_transfers = node.clone();
+        let files_dir_for_transfers = files_dir.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                let stalled: Vec<String> = {
+                    let mut node = node_for_transfers.write().await;
+                    let stalled: Vec<String> = node
+                        .incoming_transfers
+                        .iter()
+                        .filter(|(_, transfer)| transfer.last_activity.elapsed() >= CHUNK_TRANSFER_TIMEOUT)
+                        .map(|(file_id, _)| file_id.clone())
+                        .collect();
+                    for file_id in &stalled {
+                        node.abort_transfer(file_id);
+                    }
+                    stalled
+                };
+                for file_id in stalled {
+                    info!("Abandoning incomplete transfer of {}", file_id);
+                    let _ = tokio::fs::remove_file(part_path(&files_dir_for_transfers, &file_id)).await;
+                }
+            }
+        });
+
+        // Delete files whose TTL has run out
+        let node_for_expiry = node.clone();
+        let store_for_expiry = store.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                let expired = node_for_expiry.write().await.take_expired_files(unix_secs());
+                for file_id in expired {
+                    info!("File {} expired", file_id);
+                    if let Err(e) = store_for_expiry.delete(&file_id).await {
+                        error!("Failed to delete {}: {}", file_id, e);
+                    }
+                }
+            }
+        });
+
+        // Forget peers whose resource offers have gone stale
+        let node_for_sweep = node.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                let mut node = node_for_sweep.write().await;
+                node.network_resources.retain(|node_id, record| {
+                    let fresh = record.last_seen.elapsed() < RESOURCE_OFFER_TTL;
+                    if !fresh {
+                        info!("Resource offer from {} expired", node_id);
+                    }
+                    fresh
+                });
+                node.cluster_tasks.retain(|_, census| census.last_seen.elapsed() < RESOURCE_OFFER_TTL);
+            }
+        });
+
+        // Flush state to disk shortly after it changes
+        let node_for_persist = node.clone();
+        let state_path_for_persist = state_path.clone();
+        tokio::spawn(async move {
+            let state_path = state_path_for_persist;
+            loop {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+
+                let peer_store = {
+                    let mut node = node_for_persist.write().await;
+                    std::mem::take(&mut node.peer_store.dirty).then(|| node.peer_store.clone())
+                };
+                if let Some(peer_store) = peer_store {
+                    if let Err(e) = peer_store.save(&peer_store_path) {
+                        error!("Failed to save the peer store: {}", e);
+                        node_for_persist.write().await.peer_store.dirty = true;
+                    }
+                }
+
+                let state = {
+                    let mut node = node_for_persist.write().await;
+                    if !node.dirty {
+                        continue;
+                    }
+                    node.dirty = false;
+                    node.persisted_state()
+                };
+
+                if let Err(e) = save_state(&state_path, &state) {
+                    error!("Failed to persist node state: {}", e);
+                    node_for_persist.write().await.dirty = true;
+                }
+            }
+        });
+
+        // Read full lines from stdin
+        let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+
+        // Kick it off
+        info!("OpenSky node started. API available at {}://{}", scheme, api_addr);
+        if console {
+            info!("Type 'help' for available commands");
+        }
+
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+        // Check for peers that went away without a word
+        let peer_timeout = Duration::from_secs(config.networking.peer_timeout_secs);
+        let mut reconcile = tokio::time::interval(Duration::from_secs(30));
+
+        // Bootstrap peers we lost, with the wait before the next re-dial
+        let bootstrap_peers: HashMap<PeerId, Multiaddr> = config
+            .networking
+            .bootstrap
+            .iter()
+            .filter_map(|addr| parse_bootstrap_addr(addr))
+            .collect();
+        // Configured relays, which like bootstrap peers count as dialled by hand
+        let relay_peers: HashSet<PeerId> = config
+            .networking
+            .relays
+            .iter()
+            .filter_map(|addr| parse_bootstrap_addr(addr))
+            .map(|(peer, _)| peer)
+            .collect();
+        let mut redial_backoff: HashMap<PeerId, Duration> = HashMap::new();
+        let (redial_sender, mut redial_rcv) = mpsc::unbounded_channel::<PeerId>();
+
+        loop {
+            tokio::select! {
+                _ = handle.shutdown.notified() => {
+                    info!("Shutdown requested");
+                    break;
+                }
+                _ = heartbeat.tick() => {
+                    probes.heartbeat.store(unix_secs(), Ordering::Relaxed);
+                }
+                _ = reconcile.tick() => {
+                    let (silent, lost_copies) = node.write().await.prune_silent_peers(peer_timeout);
+                    for peer in silent {
+                        info!(peer_id = %peer, "Forgetting peer {}: not heard from in {:?}", peer, peer_timeout);
+                        if let Ok(peer_id) = peer.parse::<PeerId>() {
+                            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        }
+                    }
+                    for file_id in lost_copies {
+                        let _ = swarm.behaviour().replication_sender.send(file_id);
+                    }
+                }
+                line = stdin.next_line(), if console => {
+                    let line = match line {
+                        Ok(Some(line)) => line,
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("Error reading from stdin: {:?}", e);
+                            break;
+                        }
+                    };
+
+                    let mut words = line.trim().splitn(2, ' ');
+                    let command = words.next().unwrap_or_default();
+                    let args = words.next().unwrap_or_default().trim();
+
+                    match command {
+                        "" => {}
+                        "help" => {
+                            info!("Available commands:");
+                            info!("  peers - List connected peers");
+                            info!("  ping - Show the latest round-trip time to each peer");
+                            info!("  connect <multiaddr> - Dial a peer at the given address");
+                            info!("  listeners - Show the addresses this node listens on");
+                            info!("  publish <json> - Broadcast a raw OpenSkyCommand");
+                            info!("  resources - Show available, total and reserved resources");
+                            info!("  status - Show node status");
+                            info!("  tasks - List tasks running here and what they reserved");
+                            info!("  files - List stored files with their size and replicas");
+                            info!("  version - Show the build and protocol version");
+                            info!("  drain - Toggle drain mode, which stops accepting new work");
+                            info!("  quit - Exit the application");
+                        }
+                        "peers" => {
+                            let node = node.read().await;
+                            info!("Connected peers: {}", node.peers.len());
+                            for peer in &node.peers {
+                                info!("  {}", peer);
+                            }
+                        }
+                        "resources" => {
+                            let node = node.read().await;
+                            info!("Resources (available / total, reserved):");
+                            info!(
+                                "  CPU: {} / {} cores, {} reserved ({:.1}% host load)",
+                                node.available_cpu, node.total_cpu, node.reserved_cpu, node.cpu_usage_percent
+                            );
+                            info!(
+                                "  Memory: {} / {} MB, {} reserved ({} MB free on host)",
+                                node.available_memory, node.total_memory, node.reserved_memory, node.free_memory_mb
+                            );
+                            info!(
+                                "  Storage: {} / {}, {} reserved",
+                                format_gb(node.available_storage()),
+                                format_gb(node.total_storage),
+                                format_gb(node.reserved_storage)
+                            );
+                            info!("  Bandwidth: {} Mbps", node.available_bandwidth);
+                        }
+                        "version" => {
+                            let version = version();
+                            info!("OpenSky {} ({})", version.version, version.git_commit);
+                            info!("Built with {}", version.rustc_version);
+                            info!("Protocol version {}", version.protocol_version);
+                        }
+                        "status" => {
+                            let node = node.read().await;
+                            info!("Node ID: {}", node.node_id);
+                            info!("Up {}s, since {}", node.uptime_secs(), node.started_at_rfc3339());
+                            info!("Connected peers: {}", node.peers.len());
+                            info!("Active tasks: {}", node.tasks.len());
+                            info!("Stored files: {}", node.stored_files.len());
+                            if node.draining {
+                                info!("Draining: yes");
+                            }
+                        }
+                        "tasks" => {
+                            let node = node.read().await;
+                            let tasks = node.local_tasks();
+                            info!("Local tasks: {}", tasks.len());
+                            if !tasks.is_empty() {
+                                let width = tasks.iter().map(|task| task.task_id.len()).max().unwrap_or(0);
+                                info!("  {:<width$}  {:<9}  {:>3}  {:>9}  {:>4}", "TASK ID", "STATUS", "CPU", "MEMORY", "GPUS", width = width);
+                                for task in &tasks {
+                                    let (cpu, memory, gpus) = node
+                                        .running_tasks
+                                        .get(&task.task_id)
+                                        .map_or((0, 0, 0), |running| (running.cpu_cores, running.memory_mb, running.gpus));
+                                    info!(
+                                        "  {:<width$}  {:<9}  {:>3}  {:>6} MB  {:>4}",
+                                        task.task_id,
+                                        format!("{:?}", task.status),
+                                        cpu,
+                                        memory,
+                                        gpus,
+                                        width = width
+                                    );
+                                }
+                            }
+                        }
+                        "files" => {
+                            let node = node.read().await;
+                            info!("Stored files: {}", node.stored_files.len());
+                            if !node.stored_files.is_empty() {
+                                let width = node.stored_files.iter().map(|file_id| file_id.len()).max().unwrap_or(0);
+                                info!("  {:<width$}  {:>12}  {:>8}", "FILE ID", "SIZE (B)", "REPLICAS", width = width);
+                                for file_id in &node.stored_files {
+                                    let size = node.file_sizes.get(file_id).map_or("?".to_string(), |size| size.to_string());
+                                    let replicas = node.file_replicas.get(file_id).map_or(0, |replicas| replicas.len());
+                                    info!("  {:<width$}  {:>12}  {:>8}", file_id, size, replicas, width = width);
+                                }
+                            }
+                        }
+                        "drain" => {
+                            toggle_drain(&node, &probes).await;
+                            announce_now.notify_one();
+                        }
+                        "ping" => {
+                            let node = node.read().await;
+                            for peer in &node.peers {
+                                match node.peer_rtts.get(peer) {
+                                    Some(rtt) => info!("  {}: {:.1} ms", peer, rtt.as_secs_f64() * 1000.0),
+                                    None => info!("  {}: no measurement yet", peer),
+                                }
+                            }
+                        }
+                        "connect" => match args.parse::<Multiaddr>() {
+                            Ok(addr) => match swarm.dial(addr.clone()) {
+                                Ok(()) => info!("Dialing {}", addr),
+                                Err(e) => error!("Failed to dial {}: {}", addr, e),
+                            },
+                            Err(e) => error!("Invalid multiaddr {:?}: {}", args, e),
+                        },
+                        "listeners" => {
+                            info!("Listening addresses:");
+                            for addr in swarm.listeners() {
+                                info!("  {}/p2p/{}", addr, peer_id);
+                            }
+                        }
+                        "publish" => match serde_json::from_str::<OpenSkyCommand>(args) {
+                            Ok(command) => {
+                                // Peers drop commands whose origin isn't the signer
+                                if command.origin() != peer_id.to_string() {
+                                    warn!("Command origin {} is not this node, peers will drop it", command.origin());
+                                }
+                                handle.publish(&command);
+                                info!("Published {:?}", command);
+                            }
+                            Err(e) => {
+                                error!("Invalid command: {}", e);
+                                error!("Expected a JSON object keyed by the command name, for example:");
+                                error!(
+                                    "  publish {{\"TaskStatusRequest\":{{\"task_id\":\"my-task\",\"requester_id\":\"{}\"}}}}",
+                                    peer_id
+                                );
+                            }
+                        },
+                        "quit" => break,
+                        _ => error!("Unknown command: {}", line),
+                    }
+                }
+                Some(peer) = redial_rcv.recv() => {
+                    let backoff = match redial_backoff.get_mut(&peer) {
+                        Some(backoff) => backoff,
+                        None => continue,
+                    };
+                    if swarm.is_connected(&peer) {
+                        redial_backoff.remove(&peer);
+                        continue;
+                    }
+                    let addr = bootstrap_peers[&peer].clone();
+                    info!("Re-dialling bootstrap peer {} at {}", peer, addr);
+                    if let Err(e) = swarm.dial(addr) {
+                        *backoff = (*backoff * 2).min(REDIAL_BACKOFF_MAX);
+                        error!("Failed to dial bootstrap peer {}: {}; retrying in ~{:?}", peer, e, backoff);
+                        schedule_redial(&redial_sender, peer, *backoff);
+                    }
+                }
+                // Each peer that observes an address counts as a vote for it
+                Some(addr) = external_addr_rcv.recv() => {
+                    swarm.add_external_address(addr, AddressScore::Finite(1));
+                }
+                Some(request) = dispatch_rcv.recv() => {
+                    let mut node = node.write().await;
+                    // Choose workers from the peers as the swarm last saw them
+                    while let Ok(update) = node_update_rcv.try_recv() {
+                        update(&mut node);
+                    }
+                    swarm.behaviour_mut().dispatch(&mut node, request);
+                }
+                Some(update) = node_update_rcv.recv() => {
+                    update(&mut *node.write().await);
+                }
+                Some((topic, data)) = publish_rcv.recv() => {
+                    if swarm.behaviour_mut().respond_directly(&topic, &data) {
+                        continue;
+                    }
+                    let envelope = match seal_envelope(&id_keys, data) {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            error!("Failed to sign message: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic, envelope) {
+                        error!("Failed to publish message: {:?}", e);
+                    }
+                }
+                event = swarm.select_next_some() => {
+                    match event {
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            let dialable = address.with(Protocol::P2p(peer_id.into())).to_string();
+                            info!("Listening on {}", dialable);
+                            let mut node = node.write().await;
+                            if !node.listen_addresses.contains(&dialable) {
+                                node.listen_addresses.push(dialable);
+                            }
+                            probes.listening.store(true, Ordering::Relaxed);
+                        }
+                        SwarmEvent::ExpiredListenAddr { address, .. } => {
+                            let dialable = address.with(Protocol::P2p(peer_id.into())).to_string();
+                            info!("No longer listening on {}", dialable);
+                            let mut node = node.write().await;
+                            node.listen_addresses.retain(|addr| *addr != dialable);
+                            probes.listening.store(!node.listen_addresses.is_empty(), Ordering::Relaxed);
+                        }
+                        // Keep the peer set accurate for connections that didn't come from mDNS
+                        SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                            if !swarm.behaviour().allowed_peers.allows(&peer_id) {
+                                warn!(peer_id = %peer_id, "Rejecting connection from {}: not an allowed peer", peer_id);
+                                let _ = swarm.disconnect_peer_id(peer_id);
+                                continue;
+                            }
+                            info!(peer_id = %peer_id, "Connection established with: {}", peer_id);
+                            if redial_backoff.remove(&peer_id).is_some() {
+                                info!("Reconnected to bootstrap peer {}", peer_id);
+                            }
+                            // Besides configured peers we only dial those the DHT
+                            // or mDNS told us about, and mDNS has already said so
+                            let via = if bootstrap_peers.contains_key(&peer_id) || relay_peers.contains(&peer_id) {
+                                Discovery::Dial
+                            } else if endpoint.is_dialer() {
+                                Discovery::Kademlia
+                            } else {
+                                Discovery::Inbound
+                            };
+                            let mut node = node.write().await;
+                            // Only addresses we dialled are known to accept connections
+                            if endpoint.is_dialer() {
+                                node.peer_store.connected(&peer_id.to_string(), endpoint.get_remote_address());
+                            }
+                            node.connection_opened(&peer_id.to_string(), endpoint.get_remote_address().to_string(), via);
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error } => {
+                            metrics.connection_errors.with_label_values(&["outgoing"]).inc();
+                            if let DialError::Transport(attempts) = &error {
+                                let mut node = node.write().await;
+                                for (addr, _) in attempts {
+                                    node.peer_store.dial_failed(&peer_id.to_string(), addr);
+                                }
+                            }
+                            match redial_backoff.get_mut(&peer_id) {
+                                Some(backoff) => {
+                                    *backoff = (*backoff * 2).min(REDIAL_BACKOFF_MAX);
+                                    error!(
+                                        "Re-dial of bootstrap peer {} failed: {}; retrying in ~{:?}",
+                                        peer_id, error, backoff
+                                    );
+                                    schedule_redial(&redial_sender, peer_id, *backoff);
+                                }
+                                None => warn!(peer_id = %peer_id, "Failed to connect to {}: {}", peer_id, error),
+                            }
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: None, error } => {
+                            metrics.connection_errors.with_label_values(&["outgoing"]).inc();
+                            warn!("Failed to dial an unknown peer: {}", error);
+                        }
+                        SwarmEvent::IncomingConnectionError { local_addr, send_back_addr, error } => {
+                            metrics.connection_errors.with_label_values(&["incoming"]).inc();
+                            warn!("Incoming connection from {} on {} failed: {}", send_back_addr, local_addr, error);
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, endpoint, num_established, .. } => {
+                            info!(peer_id = %peer_id, "Connection closed with: {}", peer_id);
+                            let address = endpoint.get_remote_address().to_string();
+                            node.write().await.connection_closed(&peer_id.to_string(), &address, num_established);
+                            if num_established == 0 {
+                                // Gossipsub keeps re-dialling explicit peers otherwise
+                                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                                // Stay attached to the network across flaky links
+                                if bootstrap_peers.contains_key(&peer_id) && !redial_backoff.contains_key(&peer_id) {
+                                    info!("Lost bootstrap peer {}; re-dialling in ~{:?}", peer_id, REDIAL_BACKOFF_MIN);
+                                    redial_backoff.insert(peer_id, REDIAL_BACKOFF_MIN);
+                                    schedule_redial(&redial_sender, peer_id, REDIAL_BACKOFF_MIN);
+                                }
+                            }
+                        }
+                        // A listener that stops on its own means we may no longer be reachable
+                        SwarmEvent::ListenerError { listener_id, error } => {
+                            error!("Listener {:?} failed: {}", listener_id, error);
+                        }
+                        SwarmEvent::ListenerClosed { listener_id, addresses, reason } => match reason {
+                            Ok(()) => info!("Listener {:?} on {:?} closed", listener_id, addresses),
+                            Err(e) => error!("Listener {:?} on {:?} closed: {}", listener_id, addresses, e),
+                        },
+                        SwarmEvent::BannedPeer { peer_id, endpoint } => {
+                            debug!(peer_id = %peer_id, "Refused connection with banned peer {} at {}", peer_id, endpoint.get_remote_address());
+                        }
+                        event => debug!("Swarm event: {:?}", event),
+                    }
+                }
+            }
+        }
+
+        shutdown(&node, &state_path).await;
+
+        Ok(())
+    }
+}
+
+/// Reacts to commands arriving from the network. Every handler interested in
+/// a command sees it, the built-in ones first and then those registered with
+/// [`OpenSkyNodeBuilder::handler`] in order.
+#[async_trait]
+pub trait CommandHandler: Send + Sync {
+    /// Whether `handle` should be called for `cmd`; every command by default
+    fn interested(&self, _cmd: &OpenSkyCommand) -> bool {
+        true
+    }
+
+    async fn handle(&self, cmd: &OpenSkyCommand, ctx: &NodeContext);
+}
+
+/// What a [`CommandHandler`] can reach on the node that received a command
+pub struct NodeContext {
+    handle: NodeHandle,
+    executor: Arc<dyn TaskExecutor>,
+    // Incoming transfers are reassembled here before going into `store`
+    files_dir: PathBuf,
+    store: Arc<dyn BlobStore>,
+    metrics: Arc<Metrics>,
+    bandwidth: Arc<Bandwidth>,
+}
+
+impl NodeContext {
+    /// The receiving node, for publishing replies and querying its state.
+    /// Its `publish` replies on the topic the command arrived on.
+    pub fn handle(&self) -> &NodeHandle {
+        &self.handle
+    }
+
+    /// The topic the command arrived on
+    pub fn topic(&self) -> String {
+        self.handle.topic.hash().into_string()
+    }
+}
+
+// Pass `command` to every handler interested in it
+async fn dispatch_command(handlers: &[Arc<dyn CommandHandler>], command: &OpenSkyCommand, ctx: &NodeContext) {
+    for handler in handlers {
+        if handler.interested(command) {
+            handler.handle(command, ctx).await;
+        }
+    }
+}
+
+// Keeps track of the resources other nodes advertise and the tasks they run
+struct ResourceHandler;
+
+#[async_trait]
+impl CommandHandler for ResourceHandler {
+    fn interested(&self, cmd: &OpenSkyCommand) -> bool {
+        matches!(cmd, OpenSkyCommand::ResourceOffer { .. } | OpenSkyCommand::TaskCensus { .. })
+    }
+
+    async fn handle(&self, cmd: &OpenSkyCommand, ctx: &NodeContext) {
+        if let OpenSkyCommand::TaskCensus { node_id, tasks } = cmd {
+            ctx.handle.node.write().await.cluster_tasks.insert(node_id.clone(), CensusRecord {
+                tasks: tasks.clone(),
+                last_seen: Instant::now(),
+            });
+            return;
+        }
+        if let OpenSkyCommand::ResourceOffer {
+            cpu_cores,
+            memory_mb,
+            storage_gb,
+            bandwidth_mbps,
+            node_id,
+            arch,
+            os,
+            gpus,
+            image_allowlist,
+            protocol_version,
+        } = cmd
+        {
+            info!("Received resource offer from {} on {}", node_id, ctx.topic());
+            let mut node = ctx.handle.node.write().await;
+            node.events.record(NodeEvent::ResourceOfferSeen { node_id: node_id.clone() });
+            // A node on several topics offers on each of them
+            let mut topics = match node.network_resources.get(node_id) {
+                Some(record) if record.last_seen.elapsed() < RESOURCE_OFFER_TTL => record.topics.clone(),
+                _ => HashSet::new(),
+            };
+            topics.insert(ctx.topic());
+            node.network_resources.insert(node_id.clone(), ResourceRecord {
+                cpu_cores: *cpu_cores,
+                memory_mb: *memory_mb,
+                storage_gb: *storage_gb,
+                bandwidth_mbps: *bandwidth_mbps,
+                capabilities: Capabilities {
+                    arch: arch.clone(),
+                    os: os.clone(),
+                    gpus: gpus.clone(),
+                    image_allowlist: image_allowlist.clone(),
+                },
+                protocol_version: *protocol_version,
+                topics,
+                last_seen: Instant::now(),
+            });
+        }
+    }
+}
+
+// How many finished tasks are remembered for de-duplication
+const FINISHED_TASK_CACHE_SIZE: usize = 1000;
+
+// The TaskResult a finished task published, and when
+struct FinishedTask {
+    at: Instant,
+    result: OpenSkyCommand,
+}
+
+// A running task's share of the node: a concurrency permit and the CPU,
+// memory and GPUs reserved for it, taken and given back together under the node lock
+struct TaskSlot {
+    _permit: OwnedSemaphorePermit,
+    node: Arc<RwLock<NodeState>>,
+    // CPU cores, memory in MB and GPUs; taken once given back
+    reserved: Option<(u8, u32, u8)>,
+}
+
+impl TaskSlot {
+    fn release(mut self, node: &mut NodeState) {
+        if let Some((cpu_cores, memory_mb, gpus)) = self.reserved.take() {
+            node.release_task(cpu_cores, memory_mb, gpus);
+        }
+    }
+}
+
+// Dropped without `release` means the supervisor panicked or was cancelled
+// part way, so give the resources back here rather than leak them
+impl Drop for TaskSlot {
+    fn drop(&mut self) {
+        if let Some((cpu_cores, memory_mb, gpus)) = self.reserved.take() {
+            warn!("Releasing resources of a task that ended abnormally");
+            match self.node.try_write() {
+                Ok(mut node) => node.release_task(cpu_cores, memory_mb, gpus),
+                Err(_) => {
+                    let node = self.node.clone();
+                    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                        runtime.spawn(async move { node.write().await.release_task(cpu_cores, memory_mb, gpus) });
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Runs tasks sent to this node and follows up on the ones it submitted
+struct TaskHandler {
+    // Each running task holds a permit, returned when it finishes either way
+    task_slots: Arc<Semaphore>,
+    max_concurrent_tasks: usize,
+    max_task_timeout: Duration,
+    max_task_retries: u32,
+    image_allowlist: Vec<String>,
+    // A task_id seen again within this long of finishing isn't run again
+    dedup_window: Duration,
+    // Fail tasks that don't produce every output they name
+    strict_outputs: bool,
+}
+
+impl TaskHandler {
+    fn reject(&self, ctx: &NodeContext, task_id: String, requester_id: String, reason: String) {
+        ctx.handle.publish(&OpenSkyCommand::TaskReject {
+            task_id,
+            node_id: ctx.handle.peer_id.to_string(),
+            requester_id,
+            reason,
+        });
+    }
+
+    async fn run_task(&self, request: TaskRequest, ctx: &NodeContext) {
+        let node = &ctx.handle.node;
+        let peer_id = ctx.handle.peer_id;
+        let validation = {
+            let node = node.read().await;
+            validate_task_request(&request, node.total_cpu, node.total_memory).and_then(|()| {
+                match request.platform.as_deref() {
+                    Some(platform) if !node.capabilities.supports(platform) => Err(format!(
+                        "image platform {} does not match this node ({}/{})",
+                        platform, node.capabilities.os, node.capabilities.arch
+                    )),
+                    _ if request.gpus as usize > node.capabilities.gpus.len() => Err(format!(
+                        "task needs {} GPUs but this node has {}",
+                        request.gpus,
+                        node.capabilities.gpus.len()
+                    )),
+                    _ => Ok(()),
+                }
+            })
+        };
+        let TaskRequest {
+            task_id,
+            docker_image,
+            cpu_cores,
+            memory_mb,
+            gpus,
+            requester_id,
+            timeout_secs,
+            ..
+        } = request.clone();
+        info!(task_id = %task_id, peer_id = %requester_id, "Received task request: {}", task_id);
+
+        // Someone else's task under the same id would otherwise wait on a
+        // result it never gets
+        let taken = node
+            .read()
+            .await
+            .running_tasks
+            .get(&task_id)
+            .map_or(false, |running| running.requester_id != requester_id);
+        if taken {
+            self.reject(ctx, task_id, requester_id, "task_id is already running for another requester".into());
+            return;
+        }
+
+        // Gossip duplicates and retries can deliver the same task twice
+        let duplicate = {
+            let mut node = node.write().await;
+            if node.running_tasks.contains_key(&task_id) {
+                Some(None)
+            } else {
+                let dedup_window = self.dedup_window;
+                match node.finished_tasks.get(&task_id) {
+                    Some(finished) if finished.at.elapsed() < dedup_window => Some(Some(finished.result.clone())),
+                    _ => None,
+                }
+            }
+        };
+        match duplicate {
+            Some(Some(result)) => {
+                info!(task_id = %task_id, "Task {} already ran here; re-sending its result", task_id);
+                ctx.handle.publish(&result);
+                return;
+            }
+            Some(None) => {
+                info!(task_id = %task_id, "Ignoring duplicate request for running task {}", task_id);
+                return;
+            }
+            None => {}
+        }
+        node.write().await.events.record(NodeEvent::TaskReceived {
+            task_id: task_id.clone(),
+            requester_id: requester_id.clone(),
+        });
+
+        if node.read().await.shutting_down {
+            self.reject(ctx, task_id, requester_id, "node is shutting down".into());
+            return;
+        }
+
+        if node.read().await.draining {
+            self.reject(ctx, task_id, requester_id, "node is draining for maintenance".into());
+            return;
+        }
+
+        if !image_allowed(&docker_image, &self.image_allowlist) {
+            info!(task_id = %task_id, "Rejecting task {}: image {} is not allowlisted", task_id, docker_image);
+            let reason = format!("image {} is not allowed on this node", docker_image);
+            self.reject(ctx, task_id, requester_id, reason);
+            return;
+        }
+
+        if let Err(reason) = validation {
+            info!(task_id = %task_id, "Rejecting task {}: {}", task_id, reason);
+            self.reject(ctx, task_id, requester_id, reason);
+            return;
+        }
+
+        // Take a concurrency slot and the resources together, so a task
+        // never holds one without the other
+        let slot = {
+            let mut node = node.write().await;
+            match self.task_slots.clone().try_acquire_owned() {
+                Err(_) => Err(format!("node is already running {} tasks", self.max_concurrent_tasks)),
+                Ok(_) if node.free_gpus() < gpus as usize => Err("not enough free GPUs".to_string()),
+                Ok(permit) if node.available_cpu >= cpu_cores && node.available_memory >= memory_mb as u64 => {
+                    node.reserved_cpu += cpu_cores;
+                    node.reserved_memory += memory_mb as u64;
+                    node.reserved_gpus += gpus;
+                    node.refresh_available();
+                    node.tasks.push(task_id.clone());
+                    node.set_task_state(&task_id, TaskStatus::Running, Some(peer_id.to_string()));
+                    node.dirty = true;
+                    Ok(TaskSlot {
+                        _permit: permit,
+                        node: ctx.handle.node.clone(),
+                        reserved: Some((cpu_cores, memory_mb, gpus)),
+                    })
+                }
+                Ok(_) => Err("not enough free CPU or memory".to_string()),
+            }
+        };
+        let slot = match slot {
+            Ok(slot) => slot,
+            Err(reason) => {
+                info!(task_id = %task_id, "Rejecting task {}: {}", task_id, reason);
+                self.reject(ctx, task_id, requester_id, reason);
+                return;
+            }
+        };
+
+        info!(task_id = %task_id, image = %docker_image, "Executing task: {} using image: {}", task_id, docker_image);
+        let (cancel_sender, cancel_rcv) = oneshot::channel();
+        {
+            let mut node = node.write().await;
+            node.events.record(NodeEvent::TaskStarted { task_id: task_id.clone() });
+            node.running_tasks.insert(task_id.clone(), RunningTask {
+                requester_id: requester_id.clone(),
+                cancel: cancel_sender,
+                cpu_cores,
+                memory_mb,
+                gpus,
+            });
+        }
+
+        // Supervise the task off the command loop so cancels and other
+        // commands keep flowing while it runs
+        let handle = ctx.handle.clone();
+        let executor = ctx.executor.clone();
+        let metrics = ctx.metrics.clone();
+        let store = ctx.store.clone();
+        let max_task_timeout = self.max_task_timeout;
+        // Task ids come from the network, so the directory is named by a hash of one
+        let staging = ctx.files_dir.join("inputs").join(content_id(task_id.as_bytes()));
+        let has_inputs = !request.inputs.is_empty();
+        let outputs = request.outputs.clone();
+        let files_dir = ctx.files_dir.clone();
+        let strict_outputs = self.strict_outputs;
+        tokio::spawn(async move {
+            let node = &handle.node;
+
+            // Run the task in its own task so a panic can't skip the release
+            // below. Fetching its inputs counts against its timeout.
+            let mut execution = {
+                let executor = executor.clone();
+                let handle = handle.clone();
+                let staging = staging.clone();
+                let store = store.clone();
+                tokio::spawn(async move {
+                    if !has_inputs {
+                        return executor.execute(&request, None).await;
+                    }
+                    stage_inputs(&handle, store.as_ref(), &staging, &request.inputs).await?;
+                    executor.execute(&request, Some(&staging)).await
+                })
+            };
+
+            let started = Instant::now();
+            let started_at_ms = unix_millis();
+            let timeout = timeout_secs.map_or(max_task_timeout, |secs| Duration::from_secs(secs).min(max_task_timeout));
+
+            let finished = tokio::select! {
+                finished = tokio::time::timeout(timeout, &mut execution) => finished.map_err(|_| "timed out"),
+                _ = cancel_rcv => Err("cancelled"),
+            };
+            let (mut result_data, mut output) = match finished {
+                Ok(Ok(Ok(output))) => (format!("exit code {}\n{}", output.exit_code, output.stdout), Some(output)),
+                Ok(Ok(Err(e))) => (e, None),
+                Ok(Err(e)) => (format!("task execution panicked: {}", e), None),
+                Err(reason) => {
+                    execution.abort();
+                    executor.abort(&task_id).await;
+                    (reason.to_string(), None)
+                }
+            };
+            let mut success = output.as_ref().map_or(false, |output| output.exit_code == 0);
+            if has_inputs {
+                let _ = tokio::fs::remove_dir_all(&staging).await;
+            }
+
+            // Keep what it wrote. A missing output only fails the task if
+            // the node is strict about them.
+            let (output_files, warnings) = match output.as_mut() {
+                Some(output) if success && !outputs.is_empty() => {
+                    let files = std::mem::take(&mut output.files);
+                    store_outputs(node, store.as_ref(), &files_dir, &outputs, files).await
+                }
+                _ => Default::default(),
+            };
+            for warning in &warnings {
+                warn!(task_id = %task_id, "Task {}: {}", task_id, warning);
+            }
+            if strict_outputs && !warnings.is_empty() {
+                success = false;
+                result_data = warnings.join("\n");
+            }
+
+            // Release resources
+            {
+                let mut node = node.write().await;
+                slot.release(&mut node);
+                node.tasks.retain(|t| t != &task_id);
+                node.running_tasks.remove(&task_id);
+                // A task we submitted ourselves is settled by its TaskResult,
+                // which may still retry it elsewhere
+                if !node.submitted_tasks.contains_key(&task_id) {
+                    let status = if success { TaskStatus::Completed } else { TaskStatus::Failed };
+                    node.set_task_state(&task_id, status, None);
+                }
+                node.dirty = true;
+                node.events.record(if success {
+                    NodeEvent::TaskCompleted { task_id: task_id.clone() }
+                } else {
+                    NodeEvent::TaskFailed { task_id: task_id.clone(), reason: result_data.clone() }
+                });
+            }
+
+            metrics.task_duration.observe(started.elapsed().as_secs_f64());
+            metrics
+                .tasks_total
+                .with_label_values(&[if success { "success" } else { "failure" }])
+                .inc();
+
+            if !success {
+                error!(task_id = %task_id, "Task {} failed: {}", task_id, result_data);
+            }
+
+            // Send back result
+            let (stdout, stderr, exit_code) = match output {
+                Some(output) => (output.stdout, output.stderr, Some(output.exit_code)),
+                None => Default::default(),
+            };
+            node.write().await.record_task_history(TaskRecord {
+                task_id: task_id.clone(),
+                docker_image,
+                requester_id: requester_id.clone(),
+                node_id: handle.peer_id.to_string(),
+                started_at_ms,
+                finished_at_ms: unix_millis(),
+                success,
+                exit_code,
+                output: truncate_utf8(&result_data, TASK_HISTORY_OUTPUT_BYTES),
+            });
+            let result = OpenSkyCommand::TaskResult {
+                task_id: task_id.clone(),
+                success,
+                result_data,
+                node_id: handle.peer_id.to_string(),
+                requester_id,
+                stdout,
+                stderr,
+                exit_code,
+                output_files,
+                warnings,
+            };
+            handle.publish(&result);
+            node.write().await.finished_tasks.put(task_id, FinishedTask { at: Instant::now(), result });
+        });
+    }
+}
+
+#[async_trait]
+impl CommandHandler for TaskHandler {
+    fn interested(&self, cmd: &OpenSkyCommand) -> bool {
+        matches!(
+            cmd,
+            OpenSkyCommand::TaskRequest(_)
+                | OpenSkyCommand::TaskCancel { .. }
+                | OpenSkyCommand::TaskResult { .. }
+                | OpenSkyCommand::TaskStatusRequest { .. }
+                | OpenSkyCommand::TaskStatusResponse { .. }
+                | OpenSkyCommand::TaskReject { .. }
+        )
+    }
+
+    async fn handle(&self, cmd: &OpenSkyCommand, ctx: &NodeContext) {
+        let node = &ctx.handle.node;
+        match cmd {
+            OpenSkyCommand::TaskRequest(request) => self.run_task(request.clone(), ctx).await,
+            OpenSkyCommand::TaskCancel { task_id, requester_id } => {
+                // Only the requester can cancel, and only tasks we're running
+                let running = {
+                    let mut node = node.write().await;
+                    match node.running_tasks.get(task_id) {
+                        Some(task) if &task.requester_id == requester_id => node.running_tasks.remove(task_id),
+                        _ => None,
+                    }
+                };
+                if let Some(task) = running {
+                    info!(task_id = %task_id, peer_id = %requester_id, "Cancelling task {} at the request of {}", task_id, requester_id);
+                    let _ = task.cancel.send(());
+                }
+            }
+            OpenSkyCommand::TaskResult { task_id, success, result_data, node_id, exit_code, output_files, .. } => {
+                info!(task_id = %task_id, peer_id = %node_id, success, "Task {} finished on {} (success: {}): {}", task_id, node_id, success, result_data);
+                node.read().await.reputation.lock().unwrap().record_task(node_id, *success);
+                if *success {
+                    let mut node = node.write().await;
+                    match node.record_quorum_result(task_id, node_id, result_digest(*exit_code, result_data)) {
+                        Quorum::Pending => {}
+                        Quorum::Agreed => {
+                            node.submitted_tasks.remove(task_id);
+                            node.set_task_state(task_id, TaskStatus::Completed, Some(node_id.clone()));
+                            if let Some(state) = node.task_states.get_mut(task_id) {
+                                state.output_files = output_files.clone();
+                            }
+                        }
+                        Quorum::Disagreed { results, agreeing } => {
+                            error!(task_id = %task_id, "Task {} failed verification: at most {} of {} results agree", task_id, agreeing, results);
+                            node.submitted_tasks.remove(task_id);
+                            node.set_task_state(task_id, TaskStatus::Failed, None);
+                            node.events.record(NodeEvent::TaskVerificationFailed {
+                                task_id: task_id.clone(),
+                                results,
+                                agreeing,
+                            });
+                        }
+                    }
+                    return;
+                }
+                let retry = node.write().await.retry_submitted(task_id, node_id, self.max_task_retries);
+                if let Retry::Ignore = retry {
+                    let mut node = node.write().await;
+                    // Other workers may still make up a redundant task's quorum,
+                    // or already have
+                    let redundant = node.quorum_decided(task_id)
+                        || node.submitted_tasks.get(task_id).map_or(false, |s| s.request.redundancy > 1);
+                    if !redundant {
+                        node.set_task_state(task_id, TaskStatus::Failed, Some(node_id.clone()));
+                    }
+                } else {
+                    retry_task(node, &ctx.handle.dispatcher, retry, task_id, node_id, result_data).await;
+                }
+            }
+            OpenSkyCommand::TaskStatusRequest { task_id, requester_id } => {
+                // Only the worker running a task answers for it
+                let status = {
+                    let node = node.read().await;
+                    node.task_states
+                        .get(task_id)
+                        .filter(|state| state.node_id.as_deref() == Some(node.node_id.as_str()))
+                        .map(|state| state.status)
+                };
+                if let Some(status) = status {
+                    ctx.handle.publish(&OpenSkyCommand::TaskStatusResponse {
+                        task_id: task_id.clone(),
+                        status,
+                        node_id: ctx.handle.peer_id.to_string(),
+                        requester_id: requester_id.clone(),
+                    });
+                }
+            }
+            OpenSkyCommand::TaskStatusResponse { task_id, status, node_id, .. } => {
+                node.write().await.set_task_state(task_id, *status, Some(node_id.clone()));
+            }
+            OpenSkyCommand::TaskReject { task_id, node_id, reason, .. } => {
+                info!(task_id = %task_id, peer_id = %node_id, "Task {} rejected by {}: {}", task_id, node_id, reason);
+                let retry = node.write().await.retry_submitted(task_id, node_id, self.max_task_retries);
+                retry_task(node, &ctx.handle.dispatcher, retry, task_id, node_id, reason).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Accepts files other nodes want to store here and collects offers for our uploads
+struct StorageHandler;
+
+impl StorageHandler {
+    async fn receive_chunk(
+        &self,
+        ctx: &NodeContext,
+        file_id: &str,
+        node_id: &str,
+        chunk_index: u32,
+        total_chunks: u32,
+        data: &str,
+    ) {
+        let node = &ctx.handle.node;
+        let accepted = match node.write().await.incoming_transfers.get_mut(file_id) {
+            Some(transfer) if total_chunks == chunk_count(transfer.size_bytes) && chunk_index < total_chunks => {
+                transfer.last_activity = Instant::now();
+                true
+            }
+            _ => false,
+        };
+        if !accepted {
+            return;
+        }
+
+        ctx.bandwidth.record_received(data.len());
+        let bytes = match base64::decode(data) {
+            Ok(bytes) if bytes.len() <= CHUNK_SIZE => bytes,
+            Ok(_) => {
+                error!("Oversized chunk {} of file {} from {}", chunk_index, file_id, node_id);
+                return;
+            }
+            Err(e) => {
+                error!("Invalid data in chunk {} of file {}: {}", chunk_index, file_id, e);
+                return;
+            }
+        };
+        let part = part_path(&ctx.files_dir, file_id);
+        if let Err(e) = write_chunk(&part, chunk_index, &bytes).await {
+            error!("Failed to write {}: {}", part.display(), e);
+            return;
+        }
+
+        let complete = match node.write().await.incoming_transfers.get_mut(file_id) {
+            Some(transfer) => {
+                transfer.received.insert(chunk_index);
+                transfer.received.len() as u32 == total_chunks
+            }
+            None => false,
+        };
+        if !complete {
+            return;
+        }
+
+        // Every chunk is in: check the content matches its id before keeping it
+        let transfer = match file_digest(&part).await {
+            Ok(digest) if digest == file_id => match store_part(&part, file_id, ctx.store.as_ref()).await {
+                Ok(()) => node.write().await.incoming_transfers.remove(file_id),
+                Err(e) => {
+                    error!("Failed to store {}: {}", file_id, e);
+                    None
+                }
+            },
+            Ok(_) => {
+                error!("Refusing file {} from {}: content does not match its hash", file_id, node_id);
+                None
+            }
+            Err(e) => {
+                error!("Failed to hash {}: {}", part.display(), e);
+                None
+            }
+        };
+        match transfer {
+            Some(transfer) => {
+                info!("Stored file {} from {} ({} bytes)", file_id, node_id, transfer.size_bytes);
+                let mut node = node.write().await;
+                node.finish_fetch(file_id, Ok(()));
+                node.set_file_ttl(file_id, transfer.size_bytes, transfer.ttl_secs);
+                node.events.record(NodeEvent::FileStored {
+                    file_id: file_id.to_string(),
+                    size_bytes: transfer.size_bytes,
+                });
+            }
+            None => {
+                node.write().await.abort_transfer(file_id);
+                let _ = tokio::fs::remove_file(&part).await;
+            }
+        }
+    }
+}
+
+// Take on a file `node_id` pushes to us if there's room for it, both offered
+// and on disk; the storage is reserved until the chunks arrive or the
+// transfer times out
+fn accept_transfer(
+    node: &mut NodeState,
+    bandwidth: &Bandwidth,
+    disk_free: Option<u64>,
+    file_id: &str,
+    size_bytes: u64,
+    ttl_secs: Option<u64>,
+    node_id: &str,
+) -> Result<(), &'static str> {
+    if node.draining {
+        Err("node is draining for maintenance")
+    } else if !disk_has_room(node, disk_free, size_bytes) {
+        Err("not enough free disk space")
+    } else if !is_content_id(file_id) {
+        Err("file_id is not a content hash")
+    } else if node.stored_files.iter().any(|f| f == file_id) {
+        Err("file is already stored here")
+    } else if node.available_storage() < size_bytes {
+        Err("not enough free storage")
+    } else if node
+        .max_storage_per_peer
+        .map_or(false, |cap| node.peer_storage_used(node_id) + size_bytes > cap)
+    {
+        Err("requester's storage quota on this node is used up")
+    } else if !bandwidth.admit() {
+        Err("bandwidth limit reached")
+    } else if node.incoming_transfers.len() >= bandwidth.max_transfers {
+        // Peers push their chunks unasked, so these can't wait in the queue
+        Err("too many transfers in progress")
+    } else {
+        node.reserve_storage(size_bytes);
+        node.stored_files.push(file_id.to_string());
+        node.file_sizes.insert(file_id.to_string(), size_bytes);
+        node.peer_files.insert(file_id.to_string(), PeerFile {
+            owner: node_id.to_string(),
+            size_bytes,
+        });
+        node.incoming_transfers.insert(file_id.to_string(), IncomingTransfer {
+            size_bytes,
+            ttl_secs,
+            received: HashSet::new(),
+            last_activity: Instant::now(),
+        });
+        node.dirty = true;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CommandHandler for StorageHandler {
+    fn interested(&self, cmd: &OpenSkyCommand) -> bool {
+        matches!(
+            cmd,
+            OpenSkyCommand::StorageRequest { .. }
+                | OpenSkyCommand::StorageOffer { .. }
+                | OpenSkyCommand::ChunkOffer { .. }
+                | OpenSkyCommand::FileFetch { .. }
+                | OpenSkyCommand::FileHeld { .. }
+                | OpenSkyCommand::FileFetchAccept { .. }
+        )
+    }
+
+    async fn handle(&self, cmd: &OpenSkyCommand, ctx: &NodeContext) {
+        let node = &ctx.handle.node;
+        match cmd {
+            OpenSkyCommand::StorageRequest { file_id, size_bytes, ttl_secs, node_id } => {
+                info!("Received storage request for file: {}", file_id);
+
+                // Check if we have enough storage, both offered and on disk
+                let disk_free = disk_free_bytes(&ctx.files_dir);
+                let decision =
+                    accept_transfer(&mut node.write().await, &ctx.bandwidth, disk_free, file_id, *size_bytes, *ttl_secs, node_id);
+                if let Err(reason) = decision {
+                    info!("Declining storage request for {}: {}", file_id, reason);
+                }
+
+                // Send storage offer, or say why not so the requester can look elsewhere
+                ctx.handle.publish(&OpenSkyCommand::StorageOffer {
+                    file_id: file_id.clone(),
+                    node_id: ctx.handle.peer_id.to_string(),
+                    available: decision.is_ok(),
+                    reason: decision.err().map(String::from),
+                });
+            }
+            OpenSkyCommand::StorageOffer { file_id, node_id, available, reason } => {
+                // Hand accepted offers to the upload waiting on them
+                let node = node.read().await;
+                let offers = match node.storage_offers.get(file_id) {
+                    Some(offers) => offers,
+                    None => return,
+                };
+                if *available {
+                    let _ = offers.send(node_id.clone());
+                } else {
+                    info!(
+                        "{} declined to store {}: {}",
+                        node_id,
+                        file_id,
+                        reason.as_deref().unwrap_or("no reason given")
+                    );
+                }
+            }
+            OpenSkyCommand::ChunkOffer { file_id, node_id, target_id, chunk_index, total_chunks, data } => {
+                // Only accept chunks for transfers we reserved space for
+                if *target_id == ctx.handle.peer_id.to_string() {
+                    self.receive_chunk(ctx, file_id, node_id, *chunk_index, *total_chunks, data).await;
+                }
+            }
+            OpenSkyCommand::FileFetch { file_id, node_id } => {
+                // Only a complete copy is worth offering
+                let size_bytes = {
+                    let node = node.read().await;
+                    if node.incoming_transfers.contains_key(file_id) || !node.stored_files.contains(file_id) {
+                        return;
+                    }
+                    match node.file_sizes.get(file_id) {
+                        Some(size_bytes) => *size_bytes,
+                        None => return,
+                    }
+                };
+                ctx.handle.publish(&OpenSkyCommand::FileHeld {
+                    file_id: file_id.clone(),
+                    node_id: ctx.handle.peer_id.to_string(),
+                    requester_id: node_id.clone(),
+                    size_bytes,
+                });
+            }
+            OpenSkyCommand::FileHeld { file_id, node_id, requester_id, size_bytes } => {
+                // The first holder to answer a fetch of ours gets to send it
+                if *requester_id != ctx.handle.peer_id.to_string() {
+                    return;
+                }
+                let disk_free = disk_free_bytes(&ctx.files_dir);
+                let decision = {
+                    let mut node = node.write().await;
+                    if !node.input_fetches.contains_key(file_id) || node.incoming_transfers.contains_key(file_id) {
+                        return;
+                    }
+                    let decision =
+                        accept_transfer(&mut node, &ctx.bandwidth, disk_free, file_id, *size_bytes, None, node_id);
+                    if let Err(reason) = decision {
+                        node.finish_fetch(file_id, Err(reason.to_string()));
+                    }
+                    decision
+                };
+                match decision {
+                    Ok(()) => ctx.handle.publish(&OpenSkyCommand::FileFetchAccept {
+                        file_id: file_id.clone(),
+                        node_id: ctx.handle.peer_id.to_string(),
+                        holder_id: node_id.clone(),
+                    }),
+                    Err(reason) => info!("Can't take input {} from {}: {}", file_id, node_id, reason),
+                }
+            }
+            OpenSkyCommand::FileFetchAccept { file_id, node_id, holder_id } => {
+                if *holder_id != ctx.handle.peer_id.to_string() {
+                    return;
+                }
+                // Push it off the command loop, like an upload would
+                let handle = ctx.handle.clone();
+                let store = ctx.store.clone();
+                let bandwidth = ctx.bandwidth.clone();
+                let (file_id, target_id) = (file_id.clone(), node_id.clone());
+                tokio::spawn(async move {
+                    match store.get_reader(&file_id).await {
+                        Ok(Some((size_bytes, reader))) => {
+                            let (publisher, topic) = (&handle.publisher, &handle.topic);
+                            let node_id = handle.peer_id.to_string();
+                            let targets = std::slice::from_ref(&target_id);
+                            push_chunks(publisher, topic, &bandwidth, &node_id, targets, &file_id, size_bytes, reader).await
+                        }
+                        Ok(None) => error!("Can't send {} to {}: not in the store", file_id, target_id),
+                        Err(e) => error!("Can't send {} to {}: {}", file_id, target_id, e),
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+// Act on the outcome of `retry_submitted` for a task `worker` didn't complete
+async fn retry_task(
+    node: &Arc<RwLock<NodeState>>,
+    dispatcher: &mpsc::UnboundedSender<TaskRequest>,
+    retry: Retry,
+    task_id: &str,
+    worker: &str,
+    reason: &str,
+) {
+    match retry {
+        Retry::Ignore => {}
+        Retry::Again(request) => {
+            info!(task_id, peer_id = worker, "Retrying task {} on another worker after {}: {}", task_id, worker, reason);
+            let _ = dispatcher.send(request);
+        }
+        Retry::GiveUp { attempts } => {
+            error!(task_id, attempts, "Giving up on task {} after {} attempts: {}", task_id, attempts, reason);
+            let mut node = node.write().await;
+            node.set_task_state(task_id, TaskStatus::Failed, Some(worker.to_string()));
+            node.events.record(NodeEvent::TaskAbandoned {
+                task_id: task_id.to_string(),
+                attempts,
+                reason: reason.to_string(),
+            });
+        }
+    }
+}
+
+// How long shutdown waits for running tasks before giving up on them
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+// Stop taking work, give in-flight tasks a chance to finish and flush state
+async fn shutdown(node: &Arc<RwLock<NodeState>>, state_path: &Path) {
+    info!("Shutting down: no longer accepting new tasks");
+    node.write().await.shutting_down = true;
+
+    let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+    loop {
+        let running = node.read().await.tasks.len();
+        if running == 0 {
+            info!("Shutting down: all tasks finished");
+            break;
+        }
+        if Instant::now() >= deadline {
+            error!("Shutting down: giving up on {} running tasks", running);
+            break;
+        }
+        info!("Shutting down: waiting for {} running tasks", running);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    let state = node.read().await.persisted_state();
+    match save_state(state_path, &state) {
+        Ok(()) => info!("Shutting down: node state saved"),
+        Err(e) => error!("Shutting down: failed to save node state: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task_request() -> TaskRequest {
+        TaskRequest {
+            task_id: "task-1".into(),
+            docker_image: "alpine".into(),
+            cpu_cores: 1,
+            memory_mb: 256,
+            command: vec!["echo".into(), "hello".into()],
+            requester_id: "requester".into(),
+            timeout_secs: None,
+            env: HashMap::new(),
+            working_dir: None,
+            platform: None,
+            gpus: 0,
+            redundancy: 0,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn valid_task_request_is_accepted() {
+        assert_eq!(validate_task_request(&task_request(), 4, 1024), Ok(()));
+    }
+
+    #[test]
+    fn zero_resources_are_rejected() {
+        let request = TaskRequest { cpu_cores: 0, ..task_request() };
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+
+        let request = TaskRequest { memory_mb: 0, ..task_request() };
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+    }
+
+    #[test]
+    fn empty_command_is_rejected() {
+        let request = TaskRequest { command: Vec::new(), ..task_request() };
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+    }
+
+    #[test]
+    fn demands_beyond_capacity_are_rejected() {
+        let request = TaskRequest { cpu_cores: 8, ..task_request() };
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+
+        let request = TaskRequest { memory_mb: 2048, ..task_request() };
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+    }
+
+    #[test]
+    fn demands_at_capacity_are_accepted() {
+        let request = TaskRequest { cpu_cores: 4, memory_mb: 1024, ..task_request() };
+        assert_eq!(validate_task_request(&request, 4, 1024), Ok(()));
+    }
+
+    #[test]
+    fn malformed_env_is_rejected() {
+        let mut request = task_request();
+        request.env.insert("1BAD".into(), "value".into());
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+    }
+
+    #[test]
+    fn large_payloads_are_compressed_in_transit() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let command = OpenSkyCommand::Custom {
+            kind: "report".into(),
+            node_id: PeerId::from(keypair.public()).to_string(),
+            payload: serde_json::json!({ "text": "all systems nominal ".repeat(100) }),
+        };
+        let plain = serde_json::to_vec(&command).unwrap();
+
+        let sealed = seal_envelope(&keypair, plain.clone()).unwrap();
+        let envelope: SignedEnvelope = serde_json::from_slice(&sealed).unwrap();
+        assert_eq!(envelope.compression, Some(PayloadCompression::Gzip));
+        assert!(sealed.len() < plain.len());
+
+        let mut replay_guard = ReplayGuard::new(Duration::from_secs(60), NonZeroUsize::new(10).unwrap());
+        match open_envelope(&sealed, &mut replay_guard, DEFAULT_MAX_MESSAGE_BYTES) {
+            Ok(opened) => assert_eq!(serde_json::to_vec(&opened).unwrap(), plain),
+            Err(_) => panic!("failed to open a compressed envelope"),
+        }
+        // Too big once inflated
+        let mut replay_guard = ReplayGuard::new(Duration::from_secs(60), NonZeroUsize::new(10).unwrap());
+        assert!(open_envelope(&sealed, &mut replay_guard, 1024).is_err());
+    }
+
+    #[tokio::test]
+    async fn local_store_round_trips_files() {
+        let dir = env::temp_dir().join(format!("opensky-store-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let store = LocalFsStore::new(&dir);
+
+        assert!(!store.exists("file").await.unwrap());
+        assert_eq!(store.get("file").await.unwrap(), None);
+        store.put("file", b"contents".to_vec()).await.unwrap();
+        assert!(store.exists("file").await.unwrap());
+        assert_eq!(store.get("file").await.unwrap(), Some(b"contents".to_vec()));
+        let (size, mut reader) = store.get_reader("file").await.unwrap().unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!((size, contents), (8, b"contents".to_vec()));
+
+        let part = dir.join("other.part");
+        fs::write(&part, b"moved").unwrap();
+        store.put_file("other", &part).await.unwrap();
+        assert!(!part.exists());
+        assert_eq!(store.get("other").await.unwrap(), Some(b"moved".to_vec()));
+        store.delete("other").await.unwrap();
+
+        store.delete("file").await.unwrap();
+        assert!(!store.exists("file").await.unwrap());
+        // Already gone
+        store.delete("file").await.unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn task_inputs_must_be_file_ids() {
+        let file_id = content_id(b"input");
+        let request = TaskRequest { inputs: vec![file_id.clone()], ..task_request() };
+        assert_eq!(validate_task_request(&request, 4, 1024), Ok(()));
+        let request = TaskRequest { inputs: vec!["../etc/passwd".into()], ..task_request() };
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+        let request = TaskRequest { inputs: vec![file_id; MAX_TASK_INPUTS + 1], ..task_request() };
+        assert!(validate_task_request(&request, 4, 1024).is_err());
+    }
+
+    #[tokio::test]
+    async fn stored_inputs_are_staged_without_fetching() {
+        let (ctx, mut published, _dir) = context();
+        let file_id = content_id(b"input");
+        ctx.store.put(&file_id, b"input".to_vec()).await.unwrap();
+        ctx.handle.node.write().await.stored_files.push(file_id.clone());
+
+        let dir = env::temp_dir().join(format!("opensky-inputs-{}", std::process::id()));
+        stage_inputs(&ctx.handle, ctx.store.as_ref(), &dir, &[file_id.clone()]).await.unwrap();
+        assert_eq!(fs::read(dir.join(&file_id)).unwrap(), b"input");
+        assert!(published.try_recv().is_err());
+        let _ = fs::remove_dir_all(&dir);
+        let _ = ctx.store.delete(&file_id).await;
+    }
+
+    #[test]
+    fn redundant_results_need_a_majority() {
+        let mut node = NodeState::new(PeerId::random().to_string(), &NodeConfig::default(), 1024);
+        for task_id in ["agreed", "disputed"] {
+            node.submitted_tasks.insert(task_id.into(), SubmittedTask {
+                request: TaskRequest { task_id: task_id.into(), redundancy: 3, ..task_request() },
+                topic: IdentTopic::new("test"),
+                attempts: 3,
+                tried: HashSet::new(),
+            });
+        }
+
+        assert!(matches!(node.record_quorum_result("agreed", "a", "x".into()), Quorum::Pending));
+        assert!(matches!(node.record_quorum_result("agreed", "b", "x".into()), Quorum::Agreed));
+        assert_eq!(node.task_states["agreed"].quorum.as_ref().unwrap().verified, Some(true));
+        // The vote is over
+        assert!(matches!(node.record_quorum_result("agreed", "c", "y".into()), Quorum::Pending));
+
+        node.record_quorum_result("disputed", "a", "x".into());
+        node.record_quorum_result("disputed", "b", "y".into());
+        assert!(matches!(
+            node.record_quorum_result("disputed", "c", "z".into()),
+            Quorum::Disagreed { results: 3, agreeing: 1 }
+        ));
+        assert_eq!(node.task_states["disputed"].quorum.as_ref().unwrap().verified, Some(false));
+    }
+
+    #[test]
+    fn gossip_mesh_bounds_are_checked() {
+        assert_eq!(GossipConfig::default().validate(), Ok(()));
+        assert!(GossipConfig { mesh_n_low: 8, ..GossipConfig::default() }.validate().is_err());
+        assert!(GossipConfig { mesh_n_high: 4, ..GossipConfig::default() }.validate().is_err());
+        assert!(GossipConfig { history_gossip: 6, ..GossipConfig::default() }.validate().is_err());
+        assert!(GossipConfig { heartbeat_ms: 0, ..GossipConfig::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn message_floods_are_rate_limited() {
+        let mut reputation = Reputation::new(ReputationConfig {
+            messages_per_sec: 0.0,
+            message_burst: 3,
+            max_rate_violations: 2,
+            trusted_peers: vec!["trusted".to_string()],
+            ..ReputationConfig::default()
+        });
+        for _ in 0..3 {
+            assert_eq!(reputation.record_message("flooder"), Admission::Accepted);
+        }
+        assert_eq!(reputation.record_message("flooder"), Admission::RateLimited);
+        assert!(!reputation.is_banned("flooder"));
+        assert_eq!(reputation.record_message("flooder"), Admission::RateLimited);
+        assert!(reputation.is_banned("flooder"));
+        assert_eq!(reputation.record_message("flooder"), Admission::Banned);
+
+        for _ in 0..10 {
+            assert_eq!(reputation.record_message("trusted"), Admission::Accepted);
+        }
+    }
+
+    #[test]
+    fn peer_store_drops_addresses_that_keep_failing() {
+        let peer = PeerId::random();
+        let good: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let bad: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+        let mut store = PeerStore::default();
+        store.learn(&peer.to_string(), [&good, &bad.clone().with(Protocol::P2p(peer.into()))]);
+
+        for _ in 0..PEER_STORE_MAX_FAILURES {
+            store.dial_failed(&peer.to_string(), &bad);
+            store.dial_failed(&peer.to_string(), &good);
+            store.connected(&peer.to_string(), &good);
+        }
+        assert_eq!(store.dial_candidates(PEER_STORE_DIAL_COUNT), vec![(peer, vec![good.clone()])]);
+
+        for _ in 0..PEER_STORE_MAX_FAILURES {
+            store.dial_failed(&peer.to_string(), &good);
+        }
+        assert!(store.dial_candidates(PEER_STORE_DIAL_COUNT).is_empty());
+    }
+
+    #[test]
+    fn silent_peers_are_forgotten() {
+        let mut node = NodeState::new(PeerId::random().to_string(), &NodeConfig::default(), 1024);
+        node.connection_opened("connected", "/ip4/10.0.0.1/tcp/4001".into(), Discovery::Inbound);
+        node.peers.insert("crashed".into());
+        node.file_replicas.entry("file".into()).or_default().insert("crashed".into());
+
+        let (silent, lost_copies) = node.prune_silent_peers(Duration::from_secs(300));
+        assert_eq!(silent, vec!["crashed".to_string()]);
+        assert_eq!(lost_copies, vec!["file".to_string()]);
+        assert!(node.peers.contains("connected"));
+        assert!(!node.peers.contains("crashed"));
+    }
+
+    #[test]
+    fn task_history_keeps_the_latest_tasks() {
+        let mut node = NodeState::new(PeerId::random().to_string(), &NodeConfig::default(), 1024);
+        for i in 0..TASK_HISTORY_CAPACITY + 5 {
+            node.record_task_history(TaskRecord {
+                task_id: format!("task-{}", i),
+                docker_image: "alpine".into(),
+                requester_id: "requester".into(),
+                node_id: node.node_id.clone(),
+                started_at_ms: 0,
+                finished_at_ms: 0,
+                success: true,
+                exit_code: Some(0),
+                output: truncate_utf8("exit code 0\nhéllo", 14),
+            });
+        }
+        assert_eq!(node.task_history.len(), TASK_HISTORY_CAPACITY);
+        let recent = node.recent_task_history(2);
+        assert_eq!(recent[0].task_id, format!("task-{}", TASK_HISTORY_CAPACITY + 4));
+        assert_eq!(recent[1].task_id, format!("task-{}", TASK_HISTORY_CAPACITY + 3));
+        // Cutting the two-byte é in half would leave invalid UTF-8
+        assert_eq!(recent[0].output, "exit code 0\nh");
+
+        let mut restored = NodeState::new(PeerId::random().to_string(), &NodeConfig::default(), 1024);
+        restored.restore(node.persisted_state());
+        assert_eq!(restored.recent_task_history(1)[0].task_id, recent[0].task_id);
+    }
+
+    #[test]
+    fn only_fitting_workers_are_candidates() {
+        let mut node = NodeState::new(PeerId::random().to_string(), &NodeConfig::default(), 1024);
+        let worker = |cpu_cores, image_allowlist: &[&str]| ResourceRecord {
+            cpu_cores,
+            memory_mb: 1024,
+            storage_gb: 10,
+            bandwidth_mbps: 100,
+            capabilities: Capabilities {
+                arch: "amd64".into(),
+                os: "linux".into(),
+                gpus: Vec::new(),
+                image_allowlist: Some(image_allowlist.iter().map(|image| image.to_string()).collect()),
+            },
+            protocol_version: OPENSKY_PROTOCOL_VERSION,
+            topics: HashSet::from(["test".to_string()]),
+            last_seen: Instant::now(),
+        };
+        let workers = [
+            ("big", worker(8, &["alpine"])),
+            ("small", worker(2, &["alpine"])),
+            ("strict", worker(8, &["ubuntu"])),
+        ];
+        for (node_id, record) in workers {
+            node.peers.insert(node_id.into());
+            node.network_resources.insert(node_id.into(), record);
+        }
+        node.network_resources.insert("offline".into(), worker(8, &["alpine"]));
+
+        let request = TaskRequest { cpu_cores: 2, ..task_request() };
+        assert_eq!(task_candidates(&node, &request, "test"), vec!["big".to_string(), "small".to_string()]);
+        let request = TaskRequest { cpu_cores: 16, ..task_request() };
+        assert!(task_candidates(&node, &request, "test").is_empty());
+    }
+
+    #[test]
+    fn local_fit_needs_free_resources_and_an_open_node() {
+        let mut node = NodeState::new(PeerId::random().to_string(), &NodeConfig::default(), 1024);
+        node.available_cpu = 4;
+        node.available_memory = 1024;
+        node.capabilities.image_allowlist = Some(vec!["alpine".into()]);
+        assert!(node.fits_locally(&task_request()));
+        assert!(!node.fits_locally(&TaskRequest { cpu_cores: 8, ..task_request() }));
+        assert!(!node.fits_locally(&TaskRequest { docker_image: "ubuntu".into(), ..task_request() }));
+
+        node.draining = true;
+        assert!(!node.fits_locally(&task_request()));
+    }
+
+    #[tokio::test]
+    async fn transfers_past_the_limit_wait_their_turn() {
+        let bandwidth = Arc::new(Bandwidth::new(50, 1));
+        let first = bandwidth.transfer_slot().await;
+        let waiting = tokio::spawn({
+            let bandwidth = bandwidth.clone();
+            async move { bandwidth.transfer_slot().await }
+        });
+        while bandwidth.queued_transfers.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(bandwidth.active_transfers(), 1);
+
+        drop(first);
+        let _second = waiting.await.unwrap();
+        assert_eq!(bandwidth.queued_transfers.load(Ordering::Relaxed), 0);
+        assert_eq!(bandwidth.active_transfers(), 1);
+    }
+
+    #[test]
+    fn forgotten_copies_give_back_their_storage() {
+        let mut node = NodeState::new(PeerId::random().to_string(), &NodeConfig::default(), 1024);
+        let available = node.available_storage();
+        let file_id = content_id(b"hello");
+        let bandwidth = Bandwidth::new(50, 4);
+        assert_eq!(accept_transfer(&mut node, &bandwidth, None, &file_id, 5, None, "holder"), Ok(()));
+        node.incoming_transfers.remove(&file_id);
+        assert_eq!(node.available_storage(), available - 5);
+
+        node.forget_file(&file_id);
+        assert!(!node.stored_files.contains(&file_id));
+        assert_eq!(node.available_storage(), available);
+    }
+
+    // A directory removed with everything in it when dropped
+    struct TestDir(PathBuf);
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A node context whose published commands can be read back. Its files
+    // are kept until the returned `TestDir` is dropped.
+    fn context() -> (NodeContext, mpsc::UnboundedReceiver<(IdentTopic, Vec<u8>)>, TestDir) {
+        context_with(MockExecutor::new())
+    }
+
+    // Likewise, running tasks with `executor`
+    fn context_with(
+        executor: impl TaskExecutor + 'static,
+    ) -> (NodeContext, mpsc::UnboundedReceiver<(IdentTopic, Vec<u8>)>, TestDir) {
+        let peer_id = PeerId::random();
+        let (publisher, published) = mpsc::unbounded_channel();
+        let (dispatcher, _) = mpsc::unbounded_channel();
+        // Each context stores files apart, so parallel tests don't share blobs
+        let files_dir = env::temp_dir().join(format!("opensky-test-{}-{}", std::process::id(), peer_id));
+        fs::create_dir_all(&files_dir).unwrap();
+        let handle = NodeHandle {
+            peer_id,
+            node: Arc::new(RwLock::new(NodeState::new(peer_id.to_string(), &NodeConfig::default(), 1024))),
+            publisher,
+            dispatcher,
+            topic: IdentTopic::new("test"),
+            topics: vec![IdentTopic::new("test")],
+            shutdown: Arc::new(Notify::new()),
+        };
+        let context = NodeContext {
+            handle,
+            executor: Arc::new(executor),
+            store: Arc::new(LocalFsStore::new(&files_dir)),
+            files_dir: files_dir.clone(),
+            metrics: Arc::new(Metrics::new().unwrap()),
+            bandwidth: Arc::new(Bandwidth::new(50, 4)),
+        };
+        (context, published, TestDir(files_dir))
+    }
+
+    fn custom(kind: &str) -> OpenSkyCommand {
+        OpenSkyCommand::Custom {
+            kind: kind.into(),
+            node_id: "peer".into(),
+            payload: serde_json::json!({ "message": "hello" }),
+        }
+    }
+
+    // Answers every `echo` command with an `echo_reply` carrying the same payload
+    struct EchoHandler {
+        handled: AtomicU64,
+    }
+
+    #[async_trait]
+    impl CommandHandler for EchoHandler {
+        fn interested(&self, cmd: &OpenSkyCommand) -> bool {
+            matches!(cmd, OpenSkyCommand::Custom { kind, .. } if kind == "echo")
+        }
+
+        async fn handle(&self, cmd: &OpenSkyCommand, ctx: &NodeContext) {
+            if let OpenSkyCommand::Custom { payload, .. } = cmd {
+                self.handled.fetch_add(1, Ordering::Relaxed);
+                ctx.handle().publish(&OpenSkyCommand::Custom {
+                    kind: "echo_reply".into(),
+                    node_id: ctx.handle().peer_id().to_string(),
+                    payload: payload.clone(),
+                });
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_handler_sees_only_its_commands() {
+        let (ctx, mut published, _dir) = context();
+        let echo = Arc::new(EchoHandler { handled: AtomicU64::new(0) });
+        let handlers: Vec<Arc<dyn CommandHandler>> = vec![Arc::new(StorageHandler), echo.clone()];
+
+        dispatch_command(&handlers, &custom("other"), &ctx).await;
+        dispatch_command(&handlers, &custom("echo"), &ctx).await;
+
+        assert_eq!(echo.handled.load(Ordering::Relaxed), 1);
+        let (_, reply) = published.try_recv().unwrap();
+        match serde_json::from_slice(&reply).unwrap() {
+            OpenSkyCommand::Custom { kind, node_id, payload } => {
+                assert_eq!(kind, "echo_reply");
+                assert_eq!(node_id, ctx.handle().peer_id().to_string());
+                assert_eq!(payload["message"], "hello");
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        assert!(published.try_recv().is_err());
+    }
+
+    fn task_handler() -> TaskHandler {
+        TaskHandler {
+            task_slots: Arc::new(Semaphore::new(1)),
+            max_concurrent_tasks: 1,
+            max_task_timeout: Duration::from_secs(10),
+            max_task_retries: 0,
+            image_allowlist: vec!["alpine".into()],
+            dedup_window: Duration::from_secs(60),
+            strict_outputs: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn task_result_carries_the_executor_outcome() {
+        let failure = TaskOutput {
+            exit_code: 3,
+            stdout: "partial\n".into(),
+            stderr: "boom\n".into(),
+            files: HashMap::new(),
+        };
+        let (ctx, mut published, _dir) = context_with(MockExecutor::new().outcome("task-1", Ok(failure)));
+        task_handler().handle(&OpenSkyCommand::TaskRequest(task_request()), &ctx).await;
+
+        let (_, result) = tokio::time::timeout(Duration::from_secs(5), published.recv()).await.unwrap().unwrap();
+        match serde_json::from_slice(&result).unwrap() {
+            OpenSkyCommand::TaskResult { task_id, success, exit_code, stdout, stderr, .. } => {
+                assert_eq!(task_id, "task-1");
+                assert!(!success);
+                assert_eq!(exit_code, Some(3));
+                assert_eq!(stdout, "partial\n");
+                assert_eq!(stderr, "boom\n");
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        let node = ctx.handle().node.read().await;
+        assert_eq!(node.task_states["task-1"].status, TaskStatus::Failed);
+        assert_eq!(node.reserved_cpu, 0);
+    }
+
+    #[tokio::test]
+    async fn output_files_are_stored_and_missing_ones_reported() {
+        let output = TaskOutput {
+            exit_code: 0,
+            stdout: String::new(),
+            stderr: String::new(),
+            files: HashMap::from([("/out/result.txt".to_string(), b"42".to_vec())]),
+        };
+        let (ctx, mut published, _dir) = context_with(MockExecutor::new().outcome("task-1", Ok(output)));
+        let request = TaskRequest {
+            outputs: vec!["/out/result.txt".into(), "/out/missing.txt".into()],
+            ..task_request()
+        };
+        task_handler().handle(&OpenSkyCommand::TaskRequest(request), &ctx).await;
+
+        let (_, result) = tokio::time::timeout(Duration::from_secs(5), published.recv()).await.unwrap().unwrap();
+        let file_id = content_id(b"42");
+        match serde_json::from_slice(&result).unwrap() {
+            OpenSkyCommand::TaskResult { success, output_files, warnings, .. } => {
+                assert!(success);
+                assert_eq!(output_files["/out/result.txt"], file_id);
+                assert_eq!(warnings, vec!["output /out/missing.txt was not produced".to_string()]);
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        assert!(ctx.handle().node.read().await.stored_files.contains(&file_id));
+        assert_eq!(ctx.store.get(&file_id).await.unwrap(), Some(b"42".to_vec()));
+        let _ = ctx.store.delete(&file_id).await;
+    }
+
+    #[tokio::test]
+    async fn resources_are_released_when_execution_fails() {
+        let (ctx, mut published, _dir) = context_with(MockExecutor::new().outcome("task-1", Err("image pull failed".into())));
+        let baseline = ctx.handle().node.read().await.available();
+        task_handler().handle(&OpenSkyCommand::TaskRequest(task_request()), &ctx).await;
+
+        tokio::time::timeout(Duration::from_secs(5), published.recv()).await.unwrap().unwrap();
+        let node = ctx.handle().node.read().await;
+        assert_eq!(node.task_states["task-1"].status, TaskStatus::Failed);
+        assert_eq!((node.reserved_cpu, node.reserved_memory, node.reserved_gpus), (0, 0, 0));
+        assert_eq!(node.available(), baseline);
+    }
+
+    #[tokio::test]
+    async fn dropped_task_slot_gives_resources_back() {
+        let (ctx, _published, _dir) = context();
+        let node = ctx.handle().node.clone();
+        let slot = {
+            let mut node = node.write().await;
+            node.reserved_cpu += 1;
+            node.reserved_memory += 256;
+            node.refresh_available();
+            TaskSlot {
+                _permit: Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap(),
+                node: ctx.handle().node.clone(),
+                reserved: Some((1, 256, 0)),
+            }
+        };
+        drop(slot);
+
+        let node = node.read().await;
+        assert_eq!((node.reserved_cpu, node.reserved_memory), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn task_is_rejected_when_cpu_is_short() {
+        let (ctx, mut published, _dir) = context();
+        {
+            let mut node = ctx.handle().node.write().await;
+            node.reserved_cpu = node.total_cpu;
+            node.refresh_available();
+        }
+
+        task_handler().handle(&OpenSkyCommand::TaskRequest(task_request()), &ctx).await;
+
+        let (_, reply) = published.try_recv().unwrap();
+        match serde_json::from_slice(&reply).unwrap() {
+            OpenSkyCommand::TaskReject { task_id, node_id, requester_id, reason } => {
+                assert_eq!(task_id, "task-1");
+                assert_eq!(node_id, ctx.handle().peer_id().to_string());
+                assert_eq!(requester_id, "requester");
+                assert_eq!(reason, "not enough free CPU or memory");
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    }
+
+    // Memory transport ports are process-wide, so every test node gets its own
+    static NEXT_PORT: AtomicU64 = AtomicU64::new(1);
+
+    // A node on the in-memory transport with a fresh data directory, running
+    // alpine tasks on a `MockExecutor`. Returns its handle and the address
+    // other nodes can bootstrap from.
+    async fn start_node(bootstrap: &[String]) -> (NodeHandle, String) {
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let data_dir = env::temp_dir().join(format!("opensky-test-{}-{}", std::process::id(), port));
+        let _ = fs::remove_dir_all(&data_dir);
+
+        let mut config = NodeConfig::default();
+        config.networking.listen = vec![format!("/memory/{}", port)];
+        config.networking.bootstrap = bootstrap.to_vec();
+        config.networking.api_addr = ([127, 0, 0, 1], 0).into();
+        config.networking.announce_interval_secs = 1;
+        config.networking.mdns = false;
+        config.security.image_allowlist = vec!["alpine".into()];
+
+        let node = OpenSkyNode::builder()
+            .config(config)
+            .data_dir(&data_dir)
+            .memory_transport(true)
+            .executor(MockExecutor::new())
+            .build()
+            .await
+            .unwrap();
+        let handle = node.handle();
+        let addr = format!("/memory/{}/p2p/{}", port, handle.peer_id());
+        tokio::spawn(async move { node.run().await.unwrap() });
+        (handle, addr)
+    }
+
+    // `size` nodes that all bootstrap from the first
+    async fn cluster(size: usize) -> Vec<NodeHandle> {
+        let (first, addr) = start_node(&[]).await;
+        let mut nodes = vec![first];
+        for _ in 1..size {
+            nodes.push(start_node(&[addr.clone()]).await.0);
+        }
+        nodes
+    }
+
+    // Wait for `check` to hold, failing the test after 30 seconds
+    async fn eventually<F, Fut>(what: &str, mut check: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !check().await {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn nodes_discover_their_bootstrap_peer() {
+        let nodes = cluster(3).await;
+        let (first, first_id) = (&nodes[0], &nodes[0].peer_id().to_string());
+
+        eventually("the bootstrap node to see both peers", || async move { first.peers().await.len() == 2 }).await;
+        for node in &nodes[1..] {
+            eventually("a peer to see the bootstrap node", || async move {
+                node.peers().await.contains(first_id)
+            })
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn resource_offers_reach_other_nodes() {
+        let nodes = cluster(2).await;
+        let (requester, worker) = (&nodes[0], &nodes[1].peer_id().to_string());
+
+        eventually("the worker's offer", || async move {
+            requester
+                .node
+                .read()
+                .await
+                .network_resources
+                .get(worker)
+                .map_or(false, |record| record.cpu_cores > 0 && record.topics.contains("opensky-network"))
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn submitted_task_runs_on_a_peer() {
+        let nodes = cluster(2).await;
+        let (requester, worker) = (&nodes[0], &nodes[1].peer_id().to_string());
+        eventually("the worker's offer", || async move {
+            requester.node.read().await.network_resources.contains_key(worker)
+        })
+        .await;
+
+        let task_id = &requester
+            .submit_task(TaskSubmission {
+                task_id: "round-trip".into(),
+                docker_image: "alpine".into(),
+                cpu_cores: 1,
+                memory_mb: 64,
+                command: vec!["true".into()],
+                timeout_secs: None,
+                env: HashMap::new(),
+                working_dir: None,
+                platform: None,
+                gpus: 0,
+                topic: None,
+                redundancy: 0,
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+            })
+            .await
+            .unwrap();
+
+        eventually("the task's result", || async move {
+            requester.task_status(task_id).await.map_or(false, |state| {
+                state.status == TaskStatus::Completed && state.node_id.as_deref() == Some(worker.as_str())
+            })
+        })
+        .await;
+    }
+}